@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+use eyre::{eyre, Result, WrapErr};
+
+use crate::command::{Cmd, CommandRunner};
+
+/// The store paths a system depends on, with the size of each
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Closure {
+    pub paths: BTreeMap<String, u64>,
+}
+
+impl Closure {
+    /// Queries the closure of `path` with `nix path-info`
+    pub fn query(runner: &dyn CommandRunner, path: &Path) -> Result<Closure> {
+        let cmd = Cmd::new("nix")
+            .args(["path-info", "--recursive", "--size"])
+            .arg(
+                path.to_str()
+                    .ok_or_else(|| eyre!("Invalid store path {:?}", path))?,
+            );
+        let output = runner
+            .output(&cmd)
+            .wrap_err_with(|| format!("Failed to query closure of {:?}", path))?;
+        if !output.success() {
+            return Err(eyre!(
+                "Failed to query closure of {:?}: {}",
+                path,
+                output.stderr.trim()
+            ));
+        }
+        Closure::parse(&output.stdout)
+    }
+
+    /// Parses `nix path-info --size` output, a store path and its size per line
+    pub fn parse(output: &str) -> Result<Closure> {
+        let mut paths = BTreeMap::new();
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let (path, size) = fields
+                .next()
+                .zip(fields.next())
+                .ok_or_else(|| eyre!("Unexpected path-info line {:?}", line))?;
+            let size = size
+                .parse()
+                .wrap_err_with(|| format!("Invalid size in path-info line {:?}", line))?;
+            paths.insert(path.to_string(), size);
+        }
+        Ok(Closure { paths })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.paths.values().sum()
+    }
+
+    /// Versions of each package in the closure, keyed by package name
+    fn packages(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let mut packages: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for path in self.paths.keys() {
+            let (name, version) = parse_store_name(path);
+            packages.entry(name).or_default().insert(version);
+        }
+        packages
+    }
+
+    /// Packages added, removed or changing version going from `self` to `new`
+    pub fn diff(&self, new: &Closure) -> ClosureDiff {
+        let old_packages = self.packages();
+        let new_packages = new.packages();
+        let mut changes = vec![];
+
+        for (name, old_versions) in &old_packages {
+            match new_packages.get(name) {
+                None => changes.push(PackageChange::Removed {
+                    name: name.clone(),
+                    versions: old_versions.clone(),
+                }),
+                Some(new_versions) if new_versions != old_versions => {
+                    changes.push(PackageChange::Changed {
+                        name: name.clone(),
+                        old: old_versions.clone(),
+                        new: new_versions.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, new_versions) in &new_packages {
+            if !old_packages.contains_key(name) {
+                changes.push(PackageChange::Added {
+                    name: name.clone(),
+                    versions: new_versions.clone(),
+                });
+            }
+        }
+
+        ClosureDiff {
+            changes,
+            old_paths: self.paths.len(),
+            new_paths: new.paths.len(),
+            old_size: self.size(),
+            new_size: new.size(),
+        }
+    }
+}
+
+/// Splits a store path into a package name and version, nvd style: the version
+/// starts at the first `-` followed by a digit. Paths without one have no version.
+pub fn parse_store_name(path: &str) -> (String, String) {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    // drop the store hash
+    let name = base.split_once('-').map_or(base, |(_, name)| name);
+
+    let version_start = name
+        .char_indices()
+        .find(|(i, c)| {
+            *c == '-'
+                && name[i + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_digit())
+        })
+        .map(|(i, _)| i);
+    match version_start {
+        Some(i) => (name[..i].to_string(), name[i + 1..].to_string()),
+        None => (name.to_string(), String::new()),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PackageChange {
+    Added {
+        name: String,
+        versions: BTreeSet<String>,
+    },
+    Removed {
+        name: String,
+        versions: BTreeSet<String>,
+    },
+    Changed {
+        name: String,
+        old: BTreeSet<String>,
+        new: BTreeSet<String>,
+    },
+}
+
+/// What switching from one system closure to another would change
+#[derive(Debug, PartialEq, Eq)]
+pub struct ClosureDiff {
+    pub changes: Vec<PackageChange>,
+    pub old_paths: usize,
+    pub new_paths: usize,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+fn versions(versions: &BTreeSet<String>) -> String {
+    let versions: Vec<&str> = versions
+        .iter()
+        .filter(|v| !v.is_empty())
+        .map(|v| v.as_str())
+        .collect();
+    if versions.is_empty() {
+        "<none>".to_string()
+    } else {
+        versions.join(", ")
+    }
+}
+
+/// Formats a byte count in binary units, e.g. `1.5 GiB`
+pub fn human_size(bytes: i128) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes.unsigned_abs() as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let sign = if bytes < 0 { "-" } else { "" };
+    format!("{}{:.1} {}", sign, size, units[unit])
+}
+
+impl fmt::Display for ClosureDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            writeln!(f, "No version changes.")?;
+        }
+        for change in &self.changes {
+            match change {
+                PackageChange::Changed { name, old, new } => {
+                    writeln!(f, "[U] {}: {} -> {}", name, versions(old), versions(new))?
+                }
+                PackageChange::Added { name, versions: v } => {
+                    writeln!(f, "[A] {}: {}", name, versions(v))?
+                }
+                PackageChange::Removed { name, versions: v } => {
+                    writeln!(f, "[R] {}: {}", name, versions(v))?
+                }
+            }
+        }
+        let delta = self.new_size as i128 - self.old_size as i128;
+        write!(
+            f,
+            "Closure size: {} -> {} paths, {} -> {} ({}{})",
+            self.old_paths,
+            self.new_paths,
+            human_size(self.old_size as i128),
+            human_size(self.new_size as i128),
+            if delta >= 0 { "+" } else { "" },
+            human_size(delta)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_split_store_names_into_name_and_version() {
+        assert_eq!(
+            parse_store_name("/nix/store/abc123-firefox-121.0.1"),
+            ("firefox".to_string(), "121.0.1".to_string())
+        );
+        assert_eq!(
+            parse_store_name("/nix/store/abc123-python3.11-requests-2.31.0"),
+            ("python3.11-requests".to_string(), "2.31.0".to_string())
+        );
+        assert_eq!(
+            parse_store_name("/nix/store/abc123-etc"),
+            ("etc".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn should_diff_closures() {
+        let old = Closure::parse(
+            "/nix/store/a-firefox-120.0 1048576\n/nix/store/b-htop-3.2 1024\n/nix/store/c-etc 10\n",
+        )
+        .unwrap();
+        let new = Closure::parse(
+            "/nix/store/d-firefox-121.0 2097152\n/nix/store/c-etc 10\n/nix/store/e-btop-1.3 1024\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            old.diff(&new).to_string(),
+            [
+                "[U] firefox: 120.0 -> 121.0",
+                "[R] htop: 3.2",
+                "[A] btop: 1.3",
+                "Closure size: 3 -> 3 paths, 1.0 MiB -> 2.0 MiB (+1.0 MiB)",
+            ]
+            .join("\n")
+        );
+    }
+}
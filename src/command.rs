@@ -0,0 +1,313 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use eyre::{eyre, OptionExt, Result, WrapErr};
+use log::debug;
+
+/// An external command concierge will run, kept as data so it can be
+/// printed (e.g. for a dry run) as well as executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cmd {
+    pub program: String,
+    pub args: Vec<String>,
+    pub dir: Option<PathBuf>,
+}
+
+impl Cmd {
+    pub fn new<S: AsRef<str>>(program: S) -> Cmd {
+        Cmd {
+            program: program.as_ref().to_string(),
+            args: vec![],
+            dir: None,
+        }
+    }
+
+    /// Builds a command from a vec whose first element is the program
+    pub fn from_vec<S: AsRef<str>>(cmd_args: Vec<S>) -> Result<Cmd> {
+        let mut iter = cmd_args.iter().map(|s| s.as_ref());
+        let program = iter
+            .next()
+            .ok_or_eyre("Cannot build command from empty vec")?;
+        Ok(Cmd::new(program).args(iter))
+    }
+
+    pub fn arg<S: AsRef<str>>(mut self, arg: S) -> Cmd {
+        self.args.push(arg.as_ref().to_string());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Cmd
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args
+            .extend(args.into_iter().map(|s| s.as_ref().to_string()));
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Cmd {
+        self.dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(dir) = &self.dir {
+            write!(f, "cd {} && ", shell_quote(&dir.to_string_lossy()))?;
+        }
+        write!(f, "{}", shell_quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", shell_quote(arg))?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a word for display in a POSIX shell, leaving simple words untouched
+pub fn shell_quote(word: &str) -> String {
+    let is_plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,#".contains(c));
+    if is_plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Exit status and captured output of a command
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code, `None` if the process was terminated by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Executes external commands on behalf of concierge.
+///
+/// Everything that shells out goes through a runner so deployments can be
+/// printed instead of executed, and tested without nix, sudo or rsync.
+pub trait CommandRunner: Send + Sync {
+    /// Runs a command with its output streamed to the terminal, failing on a non-zero exit
+    fn run(&self, cmd: &Cmd) -> Result<()>;
+
+    /// Runs a command capturing its output. A non-zero exit is not an error here,
+    /// callers inspect `CommandOutput::code`.
+    fn output(&self, cmd: &Cmd) -> Result<CommandOutput>;
+}
+
+/// Runs commands for real on the local system
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &Cmd) -> Result<()> {
+        debug!("Running command in realtime: {}", cmd);
+
+        let mut command = Command::new(&cmd.program);
+        command
+            .args(&cmd.args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        if let Some(dir) = &cmd.dir {
+            command.current_dir(dir);
+        }
+
+        let mut child = command.spawn().wrap_err_with(|| {
+            format!(
+                "Error spawning process {} with args {:?}",
+                cmd.program, cmd.args
+            )
+        })?;
+
+        let output = child.wait().wrap_err_with(|| {
+            format!(
+                "Failed getting exit status for process {} with args {:?}",
+                &cmd.program, &cmd.args
+            )
+        })?;
+
+        check_exit_code(cmd, output.code())
+    }
+
+    fn output(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        debug!("Running command for output: {}", cmd);
+
+        let mut command = Command::new(&cmd.program);
+        command.args(&cmd.args);
+        if let Some(dir) = &cmd.dir {
+            command.current_dir(dir);
+        }
+
+        let output = command.output().wrap_err_with(|| {
+            format!(
+                "Error spawning process {} with args {:?}",
+                cmd.program, cmd.args
+            )
+        })?;
+
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// Prints commands that would change the system instead of running them.
+/// Captured commands are only used for read-only queries so they still run.
+pub struct DryRunRunner;
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, cmd: &Cmd) -> Result<()> {
+        println!("Would run: {}", cmd);
+        Ok(())
+    }
+
+    fn output(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        SystemRunner.output(cmd)
+    }
+}
+
+/// Turns an exit code into a result, with an error naming the command on failure
+pub fn check_exit_code(cmd: &Cmd, code: Option<i32>) -> Result<()> {
+    match code {
+        Some(0) => Ok(()),
+        Some(c) => Err(eyre!(
+            "Process {} with args {:?} failed with return code {}",
+            &cmd.program,
+            &cmd.args,
+            c
+        )),
+        None => Err(eyre!(
+            "Process {} with args {:?} was terminated by signal",
+            &cmd.program,
+            &cmd.args
+        )),
+    }
+}
+
+/// A scripted runner for tests. Records every command and answers with the
+/// first canned response whose pattern is contained in the command line,
+/// succeeding with no output otherwise.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeRunner {
+    calls: std::sync::Mutex<Vec<Cmd>>,
+    responses: Vec<(String, CommandOutput)>,
+}
+
+#[cfg(test)]
+impl FakeRunner {
+    pub fn new() -> FakeRunner {
+        FakeRunner::default()
+    }
+
+    /// Answer commands containing `pattern` with the given exit code and stdout
+    pub fn respond<S: AsRef<str>>(mut self, pattern: S, code: i32, stdout: S) -> FakeRunner {
+        self.responses.push((
+            pattern.as_ref().to_string(),
+            CommandOutput {
+                code: Some(code),
+                stdout: stdout.as_ref().to_string(),
+                stderr: String::new(),
+            },
+        ));
+        self
+    }
+
+    /// Fail commands containing `pattern` with exit code 1
+    pub fn fail<S: AsRef<str>>(self, pattern: S) -> FakeRunner {
+        let pattern = pattern.as_ref().to_string();
+        self.respond(pattern, 1, String::new())
+    }
+
+    /// Command lines run so far, in order
+    pub fn calls(&self) -> Vec<String> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.to_string())
+            .collect()
+    }
+
+    fn record(&self, cmd: &Cmd) -> CommandOutput {
+        self.calls.lock().unwrap().push(cmd.clone());
+        let line = cmd.to_string();
+        self.responses
+            .iter()
+            .find(|(pattern, _)| line.contains(pattern.as_str()))
+            .map(|(_, output)| output.clone())
+            .unwrap_or(CommandOutput {
+                code: Some(0),
+                ..Default::default()
+            })
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for FakeRunner {
+    fn run(&self, cmd: &Cmd) -> Result<()> {
+        let output = self.record(cmd);
+        check_exit_code(cmd, output.code)
+    }
+
+    fn output(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        Ok(self.record(cmd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_display_command_shell_quoted() {
+        let cmd = Cmd::new("rsync")
+            .args(["-ahi", "--exclude=.git", "/home/me/my config/"])
+            .arg("it's")
+            .current_dir("/tmp");
+
+        assert_eq!(
+            cmd.to_string(),
+            r"cd /tmp && rsync -ahi --exclude=.git '/home/me/my config/' 'it'\''s'"
+        );
+    }
+
+    #[test]
+    fn should_build_command_from_vec() {
+        let cmd = Cmd::from_vec(vec!["sudo", "nixos-rebuild", "switch"]).unwrap();
+
+        assert_eq!(cmd.program, "sudo");
+        assert_eq!(cmd.args, vec!["nixos-rebuild", "switch"]);
+        assert!(Cmd::from_vec(Vec::<String>::new()).is_err());
+    }
+
+    #[test]
+    fn should_record_calls_and_return_canned_output() {
+        let runner = FakeRunner::new()
+            .respond("nix eval", 0, "[\"host\"]")
+            .fail("switch");
+
+        let output = runner.output(&Cmd::new("nix").arg("eval")).unwrap();
+        let result = runner.run(&Cmd::new("sudo").args(["nixos-rebuild", "switch"]));
+
+        assert_eq!(output.stdout, "[\"host\"]");
+        assert!(result.is_err());
+        assert_eq!(
+            runner.calls(),
+            vec!["nix eval", "sudo nixos-rebuild switch"]
+        );
+    }
+}
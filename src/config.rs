@@ -1,29 +1,36 @@
+use std::fmt;
 use std::path::PathBuf;
 
+use clap::ValueEnum;
 use eyre::{eyre, Context, Result};
-use git2::Repository;
+use log::warn;
+use serde::Deserialize;
 use url::Url;
 
 use crate::fs::is_directory_empty;
-use crate::git::{is_git_repo, is_working_tree_clean, repo_has_remote, repo_status, RepoStatus};
+use crate::git::{
+    changed_files, checkout_worktree, clone_repo, is_git_repo, is_working_tree_clean, pop_stash,
+    pull_fast_forward, push, repo_has_remote, repo_status, stash_changes, GitAuth, TempWorktree,
+};
+use crate::settings::Settings;
 
-// at some later point this will be handled by some kind of
-// config management. For now, hard code all the things because it is just me using it.
-
-/// Idempotent function to clone a repo to a target dir
+/// Idempotent function to clone a repo to a target dir, then deploy from it
 /// If there is already an existing repo there, it will check that the remote matches
 /// If the remote does not match, it will return an error and user must manually remediate
-/// If the remote does matches then it does nothing, it is up to the user to manage the contents of the repo
-#[allow(dead_code)]
-fn deploy_config_repo(target_path: PathBuf, repo_url: Url) -> Result<()> {
-    let clone_repo = || {
-        Repository::clone(repo_url.as_str(), target_path.clone()).wrap_err_with(|| {
-            format!(
-                "Failed cloning repository {:?} to {:?}",
-                repo_url, target_path
-            )
-        })
-    };
+/// If the remote matches, it is brought up to date with the remote before `deploy` is run,
+/// and local commits are pushed afterwards. `flake.lock` changes made by the deployment
+/// are left to `deploy`, which commits them with `--commit-lock`. It is told whether
+/// the repo is kept in sync, i.e. the working tree was clean.
+pub fn deploy_config_repo<F>(
+    target_path: PathBuf,
+    repo_url: Url,
+    auth: &GitAuth,
+    deploy: F,
+) -> Result<()>
+where
+    F: FnOnce(bool) -> Result<()>,
+{
+    let clone_repo = || clone_repo(repo_url.as_str(), &target_path, auth);
 
     // If target dir does not exist then create it and clone repo
     if !target_path.exists() {
@@ -32,14 +39,12 @@ fn deploy_config_repo(target_path: PathBuf, repo_url: Url) -> Result<()> {
             format!("Failed creating dir for config repo at {:?}", target_path)
         })?;
         let _ = clone_repo()?;
-        return Ok(());
     }
 
     // we can now assume the dir exists
     // first, we check if it is empty, if so then clone
     if is_directory_empty(target_path.clone())? {
         let _ = clone_repo()?;
-        return Ok(());
     }
 
     // if it is not empty, bail if it is not a git repo
@@ -62,9 +67,9 @@ fn deploy_config_repo(target_path: PathBuf, repo_url: Url) -> Result<()> {
     // So we have a repo and it has the correct remote
     // There are a few scenarios here
     //   - Working tree is not empty (i.e., there are uncommited changes) - don't do anything with git and use nix to build config
-    //   - Working tree is empty and we are up to date with remote - use nix to build config, then commit and push changed flake.lock
-    //   - Working tree is empty and we are behind remote - pull from repo and use nix do build config, then commit and push changed flake.lock
-    //   - Working tree is empty and we are ahead of remote - use nix to build config, commit changed flake.lock and push to remote
+    //   - Working tree is empty and we are up to date with remote - use nix to build config
+    //   - Working tree is empty and we are behind remote - pull from repo and use nix do build config
+    //   - Working tree is empty and we are ahead of remote - use nix to build config and push to remote
 
     if !is_working_tree_clean(target_path.clone()).wrap_err_with(|| {
         format!(
@@ -73,33 +78,447 @@ fn deploy_config_repo(target_path: PathBuf, repo_url: Url) -> Result<()> {
         )
     })? {
         println!("*** Working tree is not clean, deploying config but won't interact with git.");
-        todo!("Run deployment.");
+        return deploy(false);
     }
 
     // Ok we can now assume the working tree is empty
-    // Let's figure out our status in comparison to the origin
-    let repo_status = repo_status(target_path.clone(), "origin")
+    // Let's figure out our status in comparison to the upstream
+    let repo_status = repo_status(&target_path, auth)
         .wrap_err_with(|| format!("Failed to get repo status for repo {:?}", target_path))?;
+    println!("*** Config repo is {}.", repo_status);
 
-    // before we deploy, we want to pull if we're behind
-    if let RepoStatus::Behind = repo_status {
-        println!("Local repo is behind remote. Pulling changes before deployment.");
-        todo!("Pull latest changes from remote")
+    // if the branches have diverged, then bail because we don't want to accidentally mess things up
+    if repo_status.is_diverged() {
+        return Err(eyre!("Repo {:?} is {}. Local has commits that are ahead of remote, and remote also has commits that are ahead of local. This will have to be rectified before concierge can complete deployment.", target_path.clone(), repo_status));
     }
 
-    // if repo status is complex, then bail because we don't want to accidentally mess things up
-    if let RepoStatus::Complex = repo_status {
-        return Err(eyre!("Repo {:?} has complex status. Local has commits that are ahead of remote, and remote also has commits that are ahead of local. This will have to be rectified before concierge can complete deployment.", target_path.clone()))?;
+    // before we deploy, we want to pull if we're behind
+    if repo_status.behind > 0 {
+        println!("Local repo is behind remote. Pulling changes before deployment.");
+        pull_fast_forward(&target_path, &repo_status)
+            .wrap_err_with(|| format!("Failed to pull latest changes into {:?}", target_path))?;
     }
 
     // now we can run the deployment
     println!("*** Deploying config to nix dir and building with nix.");
+    deploy(true)?;
 
-    // commit changes to flake.lock
-    println!("Updating flake.lock, and committing.");
-
-    // push to remote
-    println!("Pushing changes to remote repo.");
+    // push any commits made locally since the last deployment
+    if repo_status.ahead > 0 {
+        println!("Pushing changes to remote repo.");
+        push(&target_path, &repo_status, auth)
+            .wrap_err_with(|| format!("Failed to push {:?} to remote", target_path))?;
+    }
 
     Ok(())
 }
+
+/// What to do when the config dir has uncommitted changes at deployment
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirtyTreePolicy {
+    /// Deploy the changes
+    Allow,
+    /// Deploy the changes, listing them
+    #[default]
+    Warn,
+    /// Don't deploy
+    Refuse,
+    /// Stash the changes, deploy what is committed, then restore them
+    Stash,
+}
+
+impl fmt::Display for DirtyTreePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self
+            .to_possible_value()
+            .expect("no dirty tree policy is skipped");
+        write!(f, "{}", value.get_name())
+    }
+}
+
+/// Uncommitted files in the config dir, none if it isn't a git repo
+pub fn dirty_files(settings: &Settings) -> Result<Vec<String>> {
+    if !is_git_repo(&settings.config_path) {
+        return Ok(vec![]);
+    }
+    Ok(changed_files(&settings.config_path)?
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Applies `settings.dirty_tree_policy` before a deployment. Returns whether the
+/// changes were stashed, in which case `restore_stash` puts them back afterwards.
+/// With `settings.rev` the working tree isn't deployed, so it is left alone.
+pub fn check_dirty_tree(settings: &Settings) -> Result<bool> {
+    if settings.rev.is_some() {
+        return Ok(false);
+    }
+    let dirty = dirty_files(settings)?;
+    if dirty.is_empty() {
+        return Ok(false);
+    }
+    let listed = dirty.join(", ");
+    let config = settings.config_path_string();
+    match settings.dirty_tree_policy {
+        DirtyTreePolicy::Allow => Ok(false),
+        DirtyTreePolicy::Warn => {
+            println!(
+                "*** Warning: deploying uncommitted changes in {}: {}",
+                config, listed
+            );
+            Ok(false)
+        }
+        DirtyTreePolicy::Refuse => Err(eyre!(
+            "Config dir {} has uncommitted changes: {}. Commit or stash them, or set dirty_tree_policy.",
+            config,
+            listed
+        )),
+        DirtyTreePolicy::Stash if settings.dry_run => {
+            println!("Would stash uncommitted changes in {}: {}", config, listed);
+            Ok(false)
+        }
+        DirtyTreePolicy::Stash => {
+            println!(
+                "*** Stashing uncommitted changes in {} while deploying: {}",
+                config, listed
+            );
+            stash_changes(&settings.config_path, "concierge: changes stashed for deployment")
+        }
+    }
+}
+
+pub fn restore_stash(settings: &Settings) -> Result<()> {
+    pop_stash(&settings.config_path).wrap_err_with(|| {
+        format!(
+            "Failed to restore the changes stashed in {}, they are still in `git stash list`",
+            settings.config_path_string()
+        )
+    })
+}
+
+/// Runs `deploy` with `settings.dirty_tree_policy` applied to the config dir first,
+/// putting stashed changes back afterwards whether or not it succeeded
+pub fn with_dirty_tree_policy<F>(settings: &Settings, deploy: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let stashed = check_dirty_tree(settings)?;
+    let result = deploy();
+    if stashed {
+        match (&result, restore_stash(settings)) {
+            (Err(_), Err(error)) => warn!("{:#}", error),
+            (Ok(_), Err(error)) => return Err(error),
+            (_, Ok(())) => {}
+        }
+    }
+    result
+}
+
+/// With `settings.rev` set, checks that revision of the config repo out into a temporary
+/// worktree and deploys from there instead. The worktree goes when the returned value drops.
+pub fn checkout_rev(settings: &mut Settings) -> Result<Option<TempWorktree>> {
+    let rev = match &settings.rev {
+        Some(rev) => rev.clone(),
+        None => return Ok(None),
+    };
+    if settings.commit_lock {
+        return Err(eyre!(
+            "--commit-lock cannot be used with --rev, a revision's lock changes have no branch to go on"
+        ));
+    }
+    let worktree = checkout_worktree(&settings.config_path, &rev).wrap_err_with(|| {
+        format!(
+            "Failed to check out {} of config repo {}",
+            rev,
+            settings.config_path_string()
+        )
+    })?;
+    println!(
+        "*** Deploying {} ({:.12}) from {}",
+        rev,
+        worktree.commit.to_string(),
+        worktree.path().to_string_lossy()
+    );
+    settings.config_path = worktree.path().to_path_buf();
+    Ok(Some(worktree))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fs;
+    use std::path::Path;
+
+    use git2::{Oid, Repository, Signature};
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::command::FakeRunner;
+    use crate::history::{journaled, read_history};
+
+    fn signature() -> Signature<'static> {
+        Signature::now("Test", "test@example.com").unwrap()
+    }
+
+    fn commit_all(repo: &Repository, message: &str) -> Oid {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parents: Vec<git2::Commit> = repo
+            .head()
+            .ok()
+            .and_then(|h| h.peel_to_commit().ok())
+            .into_iter()
+            .collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature(),
+            &signature(),
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    fn push_main(repo: &Repository) {
+        repo.find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+    }
+
+    /// Creates a bare "remote" repo seeded with a flake, returning the dir and its URL
+    fn setup_remote() -> (TempDir, Url) {
+        let dir = tempdir().unwrap();
+        let bare_path = dir.path().join("remote.git");
+        Repository::init_opts(
+            &bare_path,
+            git2::RepositoryInitOptions::new()
+                .bare(true)
+                .initial_head("main"),
+        )
+        .unwrap();
+        let url = Url::from_file_path(&bare_path).unwrap();
+
+        let seed = clone_remote(&url, &dir.path().join("seed"));
+        let seed_path = seed.workdir().unwrap().to_path_buf();
+        fs::write(seed_path.join("flake.nix"), "{ }").unwrap();
+        fs::write(seed_path.join("flake.lock"), "old").unwrap();
+        commit_all(&seed, "Initial commit");
+        push_main(&seed);
+
+        (dir, url)
+    }
+
+    fn clone_remote(url: &Url, path: &Path) -> Repository {
+        let repo = Repository::clone(url.as_str(), path).unwrap();
+        repo.set_head("refs/heads/main").unwrap();
+        repo
+    }
+
+    fn remote_file(url: &Url, name: &str) -> String {
+        let repo = Repository::open_bare(url.to_file_path().unwrap()).unwrap();
+        let tree = repo
+            .find_reference("refs/heads/main")
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        let blob = tree
+            .get_name(name)
+            .unwrap()
+            .to_object(&repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        String::from_utf8(blob.content().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn should_clone_and_deploy_leaving_lock_to_deploy() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+
+        deploy_config_repo(
+            target.clone(),
+            url.clone(),
+            &GitAuth::default(),
+            |in_sync| {
+                assert!(in_sync);
+                fs::write(target.join("flake.lock"), "new").unwrap();
+                Ok(())
+            },
+        )
+        .expect("Deploy config repo");
+
+        assert_eq!(remote_file(&url, "flake.lock"), "old");
+        assert!(!is_working_tree_clean(&target).unwrap());
+    }
+
+    #[test]
+    fn should_push_local_commits_after_deploy() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+        let local = clone_remote(&url, &target);
+        fs::write(target.join("local.nix"), "{ }").unwrap();
+        commit_all(&local, "Local change");
+
+        deploy_config_repo(target.clone(), url.clone(), &GitAuth::default(), |_| Ok(()))
+            .expect("Deploy config repo");
+
+        assert_eq!(remote_file(&url, "local.nix"), "{ }");
+    }
+
+    #[test]
+    fn should_pull_before_deploy_when_behind() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+        clone_remote(&url, &target);
+
+        // someone else pushes a change
+        let other = clone_remote(&url, &remote_dir.path().join("other"));
+        fs::write(other.workdir().unwrap().join("hosts.nix"), "{ }").unwrap();
+        commit_all(&other, "Add hosts");
+        push_main(&other);
+
+        let deployed = Cell::new(false);
+        deploy_config_repo(target.clone(), url, &GitAuth::default(), |_| {
+            assert!(target.join("hosts.nix").exists());
+            deployed.set(true);
+            Ok(())
+        })
+        .expect("Deploy config repo");
+
+        assert!(deployed.get());
+    }
+
+    #[test]
+    fn should_refuse_complex_status() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+        let local = clone_remote(&url, &target);
+        fs::write(target.join("local.nix"), "{ }").unwrap();
+        commit_all(&local, "Local change");
+
+        let other = clone_remote(&url, &remote_dir.path().join("other"));
+        fs::write(other.workdir().unwrap().join("remote.nix"), "{ }").unwrap();
+        commit_all(&other, "Remote change");
+        push_main(&other);
+
+        let result = deploy_config_repo(target, url, &GitAuth::default(), |_| {
+            panic!("Should not deploy")
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_deploy_dirty_tree_without_git() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+        clone_remote(&url, &target);
+        fs::write(target.join("wip.nix"), "{ }").unwrap();
+
+        deploy_config_repo(
+            target.clone(),
+            url.clone(),
+            &GitAuth::default(),
+            |in_sync| {
+                assert!(!in_sync);
+                fs::write(target.join("flake.lock"), "new").unwrap();
+                Ok(())
+            },
+        )
+        .expect("Deploy config repo");
+
+        assert_eq!(remote_file(&url, "flake.lock"), "old");
+    }
+
+    #[test]
+    fn should_check_out_rev_into_temporary_worktree() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+        let local = clone_remote(&url, &target);
+        let released = local.head().unwrap().peel_to_commit().unwrap();
+        local
+            .tag_lightweight("v1", released.as_object(), false)
+            .unwrap();
+        fs::write(target.join("flake.lock"), "newer").unwrap();
+        let head = commit_all(&local, "Newer lock");
+
+        let mut settings = Settings::defaults().unwrap();
+        settings.config_path = target.clone();
+        settings.rev("v1".to_string());
+        let worktree = checkout_rev(&mut settings).unwrap().unwrap();
+
+        assert_eq!(worktree.commit, released.id());
+        assert_eq!(
+            fs::read_to_string(settings.config_path.join("flake.lock")).unwrap(),
+            "old"
+        );
+        assert_eq!(
+            fs::read_to_string(target.join("flake.lock")).unwrap(),
+            "newer"
+        );
+        assert_eq!(local.head().unwrap().target(), Some(head));
+        assert!(is_working_tree_clean(&target).unwrap());
+
+        drop(worktree);
+        assert!(!settings.config_path.exists());
+        assert!(local.worktrees().unwrap().is_empty());
+        assert_eq!(
+            local
+                .branches(Some(git2::BranchType::Local))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        settings.config_path = target;
+        settings.rev("missing".to_string());
+        assert!(checkout_rev(&mut settings).is_err());
+    }
+
+    #[test]
+    fn should_apply_dirty_tree_policy() {
+        let (remote_dir, url) = setup_remote();
+        let target = remote_dir.path().join("config");
+        clone_remote(&url, &target);
+        let mut settings = Settings::defaults().unwrap();
+        settings.config_path = target.clone();
+        settings.state_dir = remote_dir.path().join("state");
+        assert!(!check_dirty_tree(&settings).unwrap());
+
+        fs::write(target.join("wip.nix"), "{ }").unwrap();
+        assert!(!check_dirty_tree(&settings).unwrap());
+        journaled(&settings, "host", &FakeRunner::new(), |_, _| Ok(())).unwrap();
+        assert_eq!(read_history(&settings).unwrap()[0].dirty_files, ["wip.nix"]);
+
+        settings.dirty_tree_policy(DirtyTreePolicy::Refuse);
+        let error = check_dirty_tree(&settings).unwrap_err();
+        assert!(error.to_string().contains("uncommitted changes: wip.nix"));
+
+        settings.dirty_tree_policy(DirtyTreePolicy::Stash);
+        assert!(check_dirty_tree(&settings).unwrap());
+        assert!(is_working_tree_clean(&target).unwrap());
+        restore_stash(&settings).unwrap();
+        assert!(target.join("wip.nix").exists());
+
+        let result = with_dirty_tree_policy(&settings, || {
+            assert!(is_working_tree_clean(&target).unwrap());
+            Err(eyre!("Deploy failed"))
+        });
+        assert!(result.is_err());
+        assert!(target.join("wip.nix").exists());
+
+        settings.rev("HEAD".to_string());
+        assert!(!check_dirty_tree(&settings).unwrap());
+        assert!(target.join("wip.nix").exists());
+    }
+}
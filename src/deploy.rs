@@ -1,33 +1,64 @@
 use std::fs;
-use std::fs::{read_to_string, write, File};
-use std::io::Write;
+use std::fs::{read_to_string, File};
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
 
 use chrono::{DateTime, Local, TimeZone};
-// use colored::*;
 use eyre::{eyre, ContextCompat, OptionExt, Result, WrapErr};
-// use git2::TreeBuilder;
-use log::debug;
-use os_version::OsVersion;
-
-use crate::hash::hash_file;
+use log::{debug, warn};
+
+use crate::closure::Closure;
+use crate::command::{Cmd, CommandRunner};
+use crate::drift::{
+    already_deployed, configuration_name, detect_drift, pull_back, render_drift, write_manifest,
+};
+use crate::generations::record_deployment;
+use crate::git::{
+    changed_files, changed_files_named, commit_files, commit_signing, is_git_repo, push,
+    repo_status, CommitSigning, SignCommit,
+};
+use crate::history::journaled;
+use crate::lock::{render_diff, FlakeLock, InputChange, Locked};
+use crate::platform::{check_mode, ActivationMode, Platform};
 use crate::settings::Settings;
+use crate::status::{clear_pending_reboot, record_pending_reboot};
+use crate::sync::{plan_sync, Filter, SyncOptions};
 
-/// Deploy configuration from source to target using rsync
+/// Deploy configuration from source to target using concierge's sync engine
 /// then use platform appropriate tools to build and apply configuration
 /// using nix
-pub fn deploy_nix_configuration(settings: Settings, hostname: String) -> Result<()> {
+///
+/// With `settings.dry_run` set, nothing is changed: the files that would be tagged,
+/// flake inputs that would be updated and sync changes are printed, and `runner`
+/// is expected to print commands rather than run them.
+pub fn deploy_nix_configuration(
+    settings: Settings,
+    hostname: String,
+    platform: &dyn Platform,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    journaled(&settings, &hostname, runner, |runner, deployment_time| {
+        run_deployment(&settings, &hostname, platform, runner, deployment_time)
+    })
+}
+
+fn run_deployment(
+    settings: &Settings,
+    hostname: &str,
+    platform: &dyn Platform,
+    runner: &dyn CommandRunner,
+    deployment_time: DateTime<Local>,
+) -> Result<()> {
     // We will assume source git repo state is valid, that stuff is handled elsewhere
     // Confirm that source at least has a flake.nix
-    // Use rsync to copy from source to destination
+    // Sync from source to destination
     // Use platform appropriate tool to build and apply config
     //   - in particular, check if nix-darwin is installed on macOS and bootstrap it if not
 
-    debug!("Deploying Nix configuration with settings: {:?}", settings);
-    let os = os_version::detect().map_err(|e| eyre!("Failed to detect OS: {:?}", e))?;
-
-    let deployment_time = Local::now();
+    debug!(
+        "Deploying Nix configuration for host {} with settings: {:?}",
+        hostname, settings
+    );
 
     // check that source directory has a flake.nix
     if !settings.flake_file().exists() {
@@ -37,53 +68,402 @@ pub fn deploy_nix_configuration(settings: Settings, hostname: String) -> Result<
         )));
     }
 
+    check_mode(settings, platform)?;
+    check_lock_commit(settings)?;
+
+    if settings.dry_run {
+        println!("*** Dry run, the system will not be changed.");
+    }
+
     if settings.force_evaluation {
-        backup_file(settings.flake_file(), Local::now())
-            .wrap_err_with(|| "Failed to backup flake.nix before tagging")?;
-        tag_file_content(settings.flake_file(), deployment_time).wrap_err_with(|| {
-            format!(
-                "Failed to tag file to force evaluation: {}",
-                settings.flake_file().to_string_lossy()
-            )
-        })?;
+        if settings.dry_run {
+            println!("Would tag: {}", settings.flake_file().to_string_lossy());
+        } else {
+            backup_file(settings.flake_file(), Local::now())
+                .wrap_err_with(|| "Failed to backup flake.nix before tagging")?;
+            tag_file_content(settings.flake_file(), deployment_time).wrap_err_with(|| {
+                format!(
+                    "Failed to tag file to force evaluation: {}",
+                    settings.flake_file().to_string_lossy()
+                )
+            })?;
+        }
     };
 
     // tag files named `docker-compose.nix` to force pulling latest docker images during update
     if settings.update {
         for file in search_files_with_name(&settings.config_path, "docker-compose.yml")? {
-            tag_file_content(file, deployment_time.clone())?;
+            if settings.dry_run {
+                println!("Would tag: {}", file.to_string_lossy());
+            } else {
+                tag_file_content(file, deployment_time)?;
+            }
         }
     }
 
-    if let Some(name) = settings.update_input {
-        println!("Updating input {}", &name);
-        realtime_command_in_dir(
-            "nix",
-            settings.config_path.clone(),
-            vec!["flake", "update", name.as_str()],
-            format!("Error updating unput {}", name).as_str(),
-        )?;
+    if settings.dry_run && (settings.update || settings.update_input.is_some()) {
+        println!(
+            "Would update flake inputs: {}",
+            inputs_to_update(settings)?.join(", ")
+        );
     }
 
-    // rsync from config to install dir
-    rsync(
-        settings.config_path.clone(),
-        settings.install_path.clone(),
-        settings.sync_exclusions.clone(),
-        vec!["-ahi".to_string(), "--delete".to_string()],
-        true,
-    )
-    .wrap_err_with(|| "Failed rsync")?;
+    // keep the lock as it was to report what an update changed
+    let updating = settings.update || settings.update_input.is_some();
+    let lock_before =
+        if !settings.dry_run && (updating || settings.commit_lock) && settings.lock_file().exists()
+        {
+            FlakeLock::read(settings.lock_file())
+                .map_err(|e| warn!("Not reporting lock changes: {:#}", e))
+                .ok()
+        } else {
+            None
+        };
+
+    if let Some(name) = &settings.update_input {
+        println!("Updating input {}", name);
+        runner
+            .run(
+                &Cmd::new("nix")
+                    .args(["flake", "update", name.as_str()])
+                    .current_dir(&settings.config_path),
+            )
+            .wrap_err_with(|| format!("Error updating input {}", name))?;
+    }
 
-    let mut update_command: Vec<&str> = vec![];
+    check_drift(settings, std::io::stdin().is_terminal())?;
 
-    if let OsVersion::MacOS(_) = os {
+    if !settings.force_evaluation
+        && !settings.update
+        && already_deployed(settings, platform)
+            .wrap_err_with(|| "Failed to compare config with the last deployment")?
+    {
+        println!(
+            "Nothing to do, {} is already deployed from the same config",
+            configuration_name(platform)
+        );
+        return Ok(());
+    }
+
+    // sync from config to install dir
+    let install_options = SyncOptions {
+        filter: Filter::excluding(&settings.sync_exclusions)?,
+        delete: true,
+        ..Default::default()
+    };
+    if settings.dry_run {
+        let changes = plan_sync(
+            &settings.config_path,
+            &settings.install_path,
+            &install_options,
+        )
+        .wrap_err_with(|| "Failed to compute sync changes")?;
+        println!(
+            "Would sync {} -> {} ({} changes)",
+            settings.config_path_string(),
+            settings.install_path_string(),
+            changes.len()
+        );
+        for change in changes {
+            println!("    {}", change);
+        }
+    }
+
+    // snapshot the install dir so it can be restored if the deployment fails
+    let snapshot = if settings.install_path.exists() {
+        let snapshot = settings.snapshot_path();
+        runner
+            .run(&sync_command(
+                &settings.install_path,
+                &snapshot,
+                &SyncOptions {
+                    delete: true,
+                    ..Default::default()
+                },
+                true,
+            )?)
+            .wrap_err_with(|| format!("Failed to snapshot {:?}", settings.install_path))?;
+        Some(snapshot)
     } else {
+        None
+    };
+    let generation_before = platform.current_generation(settings);
+
+    if let Err(error) = install_and_activate(settings, platform, runner, &install_options) {
+        return Err(roll_back(
+            settings,
+            platform,
+            runner,
+            snapshot.as_deref(),
+            generation_before,
+            error,
+        ));
+    }
+
+    // the configuration is active now, so failing to record it doesn't fail the deployment
+    if !settings.dry_run {
+        if settings.mode.creates_generation() {
+            if let Err(error) = record_deployment(settings, runner) {
+                warn!("Failed to record deployment for rollback: {:#}", error);
+            }
+        }
+        let reboot = match settings.mode {
+            ActivationMode::Boot => record_pending_reboot(settings),
+            ActivationMode::Switch => clear_pending_reboot(settings),
+            _ => Ok(()),
+        };
+        if let Err(error) = reboot {
+            warn!("Failed to record pending reboot: {:#}", error);
+        }
+        if let Err(error) = write_manifest(settings, platform) {
+            warn!("Failed to record installed files: {:#}", error);
+        }
+    }
+
+    // the snapshot was taken as root, so it is removed as root too
+    if let Some(snapshot) = snapshot.filter(|s| !settings.dry_run && s.exists()) {
+        runner
+            .run(&Cmd::new("sudo").args(["rm", "-rf", &snapshot.to_string_lossy()]))
+            .wrap_err_with(|| format!("Failed to remove snapshot {:?}", snapshot))?;
+    }
+
+    // a build is only a check, so the config dir keeps its locks
+    if settings.mode == ActivationMode::Build {
+        return Ok(());
+    }
+
+    // pull back any changed flake.lock files
+    let lock_options = SyncOptions {
+        filter: Filter::new()
+            .include("*.lock")?
+            .include("*/")?
+            .exclude("*")?,
+        prune_empty_dirs: true,
+        ..Default::default()
+    };
+    runner
+        .run(&sync_command(
+            &settings.install_path,
+            &settings.config_path,
+            &lock_options,
+            false,
+        )?)
+        .wrap_err_with(|| "Failed syncing updated .lock files back to config dir")?;
+
+    if let Some(before) = lock_before.as_ref().filter(|_| updating) {
+        let after = FlakeLock::read(settings.lock_file())?;
+        println!("{}", render_diff(&before.diff(&after)?));
+    }
+
+    if settings.commit_lock {
+        if settings.dry_run {
+            println!("Would commit and push changed flake.lock files");
+        } else {
+            commit_locks(
+                settings,
+                hostname,
+                lock_before.as_ref(),
+                deployment_time,
+                runner,
+            )
+            .wrap_err_with(|| "Failed to commit flake.lock changes")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// With `commit_lock`, refuses to deploy from a config path with changes other than
+/// to `flake.lock` files, so the lock commit never has to leave anything behind
+fn check_lock_commit(settings: &Settings) -> Result<()> {
+    if !settings.commit_lock {
+        return Ok(());
+    }
+    if !is_git_repo(&settings.config_path) {
+        return Err(eyre!(
+            "Cannot commit flake.lock, config dir {} is not a git repo",
+            settings.config_path_string()
+        ));
+    }
+    let dirty: Vec<String> = changed_files(&settings.config_path)?
+        .iter()
+        .filter(|p| p.file_name().is_some_and(|n| n != "flake.lock"))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    if !dirty.is_empty() {
+        return Err(eyre!(
+            "Not deploying with --commit-lock, config dir {} has uncommitted changes: {}",
+            settings.config_path_string(),
+            dirty.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Commits the `flake.lock` files changed in the config path, signed if the repo's
+/// git config asks for it, and pushes the commit to the branch's upstream
+fn commit_locks(
+    settings: &Settings,
+    hostname: &str,
+    lock_before: Option<&FlakeLock>,
+    deployment_time: DateTime<Local>,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let config_path = &settings.config_path;
+    let locks = changed_files_named(config_path, "flake.lock")?;
+    if locks.is_empty() {
+        println!("No flake.lock changes to commit");
+        return Ok(());
+    }
+    let changes = match lock_before {
+        Some(before) if settings.lock_file().exists() => {
+            before.diff(&FlakeLock::read(settings.lock_file())?)?
+        }
+        _ => vec![],
+    };
+    let message = lock_commit_message(hostname, &locks, &changes, deployment_time);
+
+    let signing = commit_signing(config_path)?;
+    let sign = |content: &str| match &signing {
+        Some(signing) => sign_commit(signing, content, runner),
+        None => Err(eyre!("Commit signing is not configured")),
+    };
+    let commit = commit_files(
+        config_path,
+        &locks,
+        &message,
+        signing.as_ref().map(|_| &sign as SignCommit),
+    )?;
+    println!(
+        "*** Committed flake.lock changes as {:.12}",
+        commit.to_string()
+    );
+
+    let auth = settings.git_auth();
+    let status = repo_status(config_path, &auth)?;
+    if status.behind > 0 {
+        return Err(eyre!(
+            "Not pushing the flake.lock commit, config repo is {}. Pull and push it by hand.",
+            status
+        ));
+    }
+    push(config_path, &status, &auth)?;
+    println!("*** Pushed flake.lock changes to {}", status.upstream);
+    Ok(())
+}
+
+fn lock_commit_message(
+    hostname: &str,
+    locks: &[PathBuf],
+    changes: &[InputChange],
+    deployment_time: DateTime<Local>,
+) -> String {
+    let mut message = format!("Update flake.lock on {}\n", hostname);
+    if !changes.is_empty() {
+        message.push_str("\nUpdated inputs:\n");
+        for change in changes {
+            let rev = |l: Option<&Locked>| l.map_or("-".to_string(), Locked::short_rev);
+            message.push_str(&format!(
+                "    {}: {} \u{2192} {}\n",
+                change.name,
+                rev(change.old.as_ref()),
+                rev(change.new.as_ref())
+            ));
+        }
+    }
+    message.push_str("\nLock files:\n");
+    for lock in locks {
+        message.push_str(&format!("    {}\n", lock.to_string_lossy()));
+    }
+    message.push_str(&format!("\nDeployed {}\n", deployment_time.to_rfc3339()));
+    message
+}
+
+/// Signs a commit's contents with gpg or ssh-keygen, returning the armored signature
+fn sign_commit(
+    signing: &CommitSigning,
+    content: &str,
+    runner: &dyn CommandRunner,
+) -> Result<String> {
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("commit");
+    fs::write(&file, content)?;
+    let cmd = signing.command(&file);
+    let output = runner.output(&cmd)?;
+    if !output.success() {
+        return Err(eyre!("{} failed: {}", cmd, output.stderr.trim()));
+    }
+    let signature_file = signing.signature_file(&file);
+    fs::read_to_string(&signature_file)
+        .wrap_err_with(|| format!("Failed to read signature {:?}", signature_file))
+}
+
+/// Stops edits made directly in the install path since the last deployment from
+/// being silently overwritten. They are overwritten with `--force`, otherwise
+/// pulled back into the config path if an `interactive` user agrees.
+fn check_drift(settings: &Settings, interactive: bool) -> Result<()> {
+    let drift =
+        detect_drift(settings).wrap_err_with(|| "Failed to check install path for edits")?;
+    if drift.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "{} was edited since the last deployment:\n{}",
+        settings.install_path_string(),
+        render_drift(&drift)
+    );
+    if settings.force {
+        println!("*** Overwriting the edits, --force was given");
+        return Ok(());
+    }
+    if settings.dry_run {
+        println!("Would refuse to deploy without --force");
+        return Ok(());
+    }
+    // with --rev the config path is a temporary worktree, edits pulled back into it are lost
+    if let Some(rev) = &settings.rev {
+        return Err(eyre!(
+            "Refusing to overwrite edits to {} deploying {}, pull them back with \
+             `concierge drift --pull` first or overwrite them with --force",
+            settings.install_path_string(),
+            rev
+        ));
+    }
+    if interactive && confirm("Pull the edits back into the config path?")? {
+        return pull_back(settings, &drift);
+    }
+    Err(eyre!(
+        "Refusing to overwrite edits to {}, pull them back with `concierge drift --pull` \
+         or overwrite them with --force",
+        settings.install_path_string()
+    ))
+}
+
+/// Syncs the config into the install path, updates flake inputs if asked to
+/// and activates the configuration. Any failure here is rolled back.
+fn install_and_activate(
+    settings: &Settings,
+    platform: &dyn Platform,
+    runner: &dyn CommandRunner,
+    install_options: &SyncOptions,
+) -> Result<()> {
+    runner
+        .run(&sync_command(
+            &settings.config_path,
+            &settings.install_path,
+            install_options,
+            true,
+        )?)
+        .wrap_err_with(|| "Failed syncing configuration to installation location")?;
+
+    let mut update_command: Vec<&str> = vec![];
+
+    if platform.needs_sudo() {
         update_command.push("sudo");
     }
 
     // run flake update if update option is true
-    update_command.extend(vec!["nix", "flake", "update", "--flake"]);
+    update_command.extend(vec!["nix", "flake", "update"]);
 
     if settings.show_trace {
         update_command.push("-vv");
@@ -97,122 +477,233 @@ pub fn deploy_nix_configuration(settings: Settings, hostname: String) -> Result<
         update_command.push("--show-trace");
     }
 
+    update_command.push("--flake");
     update_command.push(
         settings
             .install_path
             .as_os_str()
             .to_str()
-            .ok_or_eyre("Failed to reslove installation path for deployment command")?,
+            .ok_or_eyre("Failed to resolve installation path for deployment command")?,
     );
 
     if settings.update {
-        realtime_command_vec(
-            update_command,
-            "Failed syncing configuration to installation location",
-        )?;
+        runner
+            .run(&Cmd::from_vec(update_command)?)
+            .wrap_err_with(|| "Failed updating flake inputs")?;
     };
 
-    match os {
-        OsVersion::Linux(l) if l.distro == "nixos" => realtime_command(
-            "sudo",
-            vec!["nixos-rebuild", "switch"],
-            "Failed to bulid and apply Nix configuration",
-        )?,
-        OsVersion::MacOS(_) => realtime_command(
-            "darwin-rebuild",
-            vec![
-                "switch",
-                "--flake",
-                settings
-                    .install_path
-                    .as_os_str()
-                    .to_str()
-                    .wrap_err_with(|| {
-                        format!(
-                            "Failed to convert install path to string: {:?}",
-                            settings.install_path
-                        )
-                    })?,
-            ],
-            "Failed to build and apply nix configuration",
-        )?,
-        _ => return Err(eyre!("Unsupported OS")),
+    if platform
+        .prepare(settings, runner)
+        .wrap_err_with(|| format!("Failed to prepare {} for deployment", platform.kind()))?
+    {
+        return Ok(());
     }
 
-    // pull back any changed flake.lock files
-    rsync(
-        settings.install_path,
-        settings.config_path,
-        vec!["*"],
-        vec!["-aim", "--include='*.lock'", "--include='*/'"],
-        true,
-    )
-    .wrap_err_with(|| "Failed syncing updated .lock files back to config dir")?;
+    // build first so what is about to change can be shown before switching
+    if !settings.dry_run {
+        fs::create_dir_all(&settings.state_dir)
+            .wrap_err_with(|| format!("Failed to create state dir {:?}", settings.state_dir))?;
+    }
+    runner
+        .run(&platform.build_command(settings)?)
+        .wrap_err_with(|| "Failed to build Nix configuration")?;
 
+    if !settings.dry_run {
+        if let Err(error) = show_closure_diff(settings, runner) {
+            warn!("Failed to compare with the current system: {:#}", error);
+        }
+    }
+    if settings.mode == ActivationMode::Build {
+        println!(
+            "Built configuration, linked at {}",
+            settings.build_result_path().to_string_lossy()
+        );
+        return Ok(());
+    }
+    if !settings.dry_run
+        && settings.confirm
+        && !confirm(&format!(
+            "Activate the new configuration with {}?",
+            settings.mode
+        ))?
+    {
+        return Err(eyre!("Activation declined, configuration not activated"));
+    }
+
+    runner
+        .run(&platform.activate_command(settings)?)
+        .wrap_err_with(|| "Failed to build and apply Nix configuration")?;
+
+    Ok(())
+}
+
+/// Prints the packages that switching to the built system would add, remove or change
+fn show_closure_diff(settings: &Settings, runner: &dyn CommandRunner) -> Result<()> {
+    if !settings.current_system.exists() {
+        println!(
+            "No current system at {:?} to compare with",
+            settings.current_system
+        );
+        return Ok(());
+    }
+    let current = Closure::query(runner, &settings.current_system)?;
+    let new = Closure::query(runner, &settings.build_result_path())?;
+    println!("Changes from the current system:");
+    println!("{}", current.diff(&new));
     Ok(())
 }
 
-fn rsync<P: AsRef<Path>, S: AsRef<str>>(
+/// Asks a yes/no question on the terminal, anything but yes is a no
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .wrap_err_with(|| "Failed to read confirmation")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Restores the install path from its snapshot and, if enabled and the failed
+/// deployment created a new generation, rolls the system back to the previous one.
+/// Returns the original error with what was rolled back added as context.
+fn roll_back(
+    settings: &Settings,
+    platform: &dyn Platform,
+    runner: &dyn CommandRunner,
+    snapshot: Option<&Path>,
+    generation_before: Option<u32>,
+    error: eyre::Report,
+) -> eyre::Report {
+    println!("*** Deployment failed, rolling back: {:#}", error);
+    let mut rolled_back = vec![];
+
+    match snapshot {
+        Some(snapshot) => {
+            let restore = sync_command(
+                snapshot,
+                &settings.install_path,
+                &SyncOptions {
+                    delete: true,
+                    ..Default::default()
+                },
+                true,
+            )
+            .and_then(|cmd| runner.run(&cmd));
+            if let Err(restore_error) = restore {
+                return error.wrap_err(format!(
+                    "Deployment failed and restoring {:?} from snapshot {:?} also failed: {:#}",
+                    settings.install_path, snapshot, restore_error
+                ));
+            }
+            println!(
+                "*** Restored {} from snapshot {}",
+                settings.install_path_string(),
+                snapshot.to_string_lossy()
+            );
+            rolled_back.push(format!("restored {}", settings.install_path_string()));
+        }
+        None => println!(
+            "*** {} did not exist before deployment, nothing to restore",
+            settings.install_path_string()
+        ),
+    }
+
+    if settings.rollback_system {
+        let generation_after = platform.current_generation(settings);
+        match platform.rollback_command() {
+            None => println!(
+                "*** {} has no system generations, not rolling back the system",
+                platform.kind()
+            ),
+            Some(_) if generation_after == generation_before => {
+                println!("*** System generation unchanged, not rolling back the system")
+            }
+            Some(rollback_command) => {
+                if let Err(rollback_error) = runner.run(&rollback_command) {
+                    return error.wrap_err(format!(
+                        "Deployment failed and rolling back the system generation also failed: {:#}",
+                        rollback_error
+                    ));
+                }
+                println!("*** Rolled system back to the previous generation");
+                rolled_back.push("rolled back the system generation".to_string());
+            }
+        }
+    }
+
+    if rolled_back.is_empty() {
+        error.wrap_err("Deployment failed, nothing was rolled back")
+    } else {
+        error.wrap_err(format!(
+            "Deployment failed and was rolled back: {}",
+            rolled_back.join(", ")
+        ))
+    }
+}
+
+/// Names of the flake inputs an update would touch, according to the config's flake.lock
+fn inputs_to_update(settings: &Settings) -> Result<Vec<String>> {
+    let lock_file = settings.lock_file();
+    let mut inputs = if settings.update && lock_file.exists() {
+        FlakeLock::read(&lock_file)?.root_inputs()?
+    } else if settings.update {
+        vec!["all inputs (no flake.lock yet)".to_string()]
+    } else {
+        vec![]
+    };
+
+    if let Some(name) = &settings.update_input {
+        if !inputs.contains(name) {
+            inputs.push(name.clone());
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Builds a command running concierge's own sync engine as a separate process,
+/// so that it can be given sudo to write to the installation location.
+pub(crate) fn sync_command<P: AsRef<Path>>(
     source: P,
     destination: P,
-    exclusions: Vec<S>,
-    params: Vec<S>,
+    options: &SyncOptions,
     sudo: bool,
-) -> Result<()> {
+) -> Result<Cmd> {
     let source = source.as_ref();
     let destination = destination.as_ref();
-    let exclusions: Vec<&str> = exclusions.iter().map(|s| s.as_ref()).collect();
-    let params: Vec<&str> = params.iter().map(|s| s.as_ref()).collect();
-
-    let source_str = source
-        .to_str()
-        .wrap_err_with(|| format!("Failed to get source path string {:?}", source))?;
-    let destination_str = destination
-        .to_str()
-        .wrap_err_with(|| format!("Failed to get destination path string {:?}", destination))?;
-
-    let exclusions_string = exclusions.iter().fold(String::new(), |mut acc, item| {
-        acc.push_str(" --exclude=");
-        acc.push_str(format!("'{}'", item).as_str());
-        acc
-    });
-
-    let extra_params_string = params.iter().fold(String::new(), |mut acc, item| {
-        acc.push_str(" ");
-        acc.push_str(item);
-        acc
-    });
-
-    let mut rsync_command = format!(
-        "rsync {} {} {}/ {}/",
-        extra_params_string, exclusions_string, source_str, destination_str
-    );
+    let concierge = std::env::current_exe()
+        .wrap_err_with(|| "Failed to get path of the concierge executable")?;
 
-    if sudo {
-        rsync_command = format! {"{} {}", "sudo", rsync_command};
+    let mut cmd = if sudo {
+        Cmd::new("sudo").arg(concierge.to_string_lossy())
+    } else {
+        Cmd::new(concierge.to_string_lossy())
+    };
+    cmd = cmd.arg("sync");
+    for rule in &options.filter.rules {
+        cmd = cmd.arg(format!("--filter={}", rule));
+    }
+    if options.delete {
+        cmd = cmd.arg("--delete");
+    }
+    if options.prune_empty_dirs {
+        cmd = cmd.arg("--prune-empty-dirs");
     }
 
-    debug!("Running rsync with command {}", &rsync_command);
+    debug!("Syncing {:?} to {:?} with {}", source, destination, cmd);
 
-    realtime_command(
-        "nix-shell",
-        vec!["-p", "rsync", "--run", &rsync_command],
-        "Failed executing rsync",
-    )
+    Ok(cmd
+        .arg(source.to_string_lossy())
+        .arg(destination.to_string_lossy()))
 }
 
-// fn run_compose2nix<P: AsRef<Path>, S: AsRef<str>>(dir: P, name: S, failure_msg: S) -> Result<()> {
-//     realtime_command_in_dir(
-//         "compose2nix",
-//         dir,
-//         vec!["-project", name.as_ref()],
-//         failure_msg.as_ref(),
-//     )
-// }
-
 // Recursively searches directory tree from specified root for files with a specified name
 // Returns a `Vec` of `PathBuf`
-fn search_files_with_name<P: AsRef<Path>, S: AsRef<str>>(root: P, name: S) -> Result<Vec<PathBuf>> {
+pub(crate) fn search_files_with_name<P: AsRef<Path>, S: AsRef<str>>(
+    root: P,
+    name: S,
+) -> Result<Vec<PathBuf>> {
     let root = root.as_ref();
     let name = name.as_ref();
 
@@ -236,122 +727,6 @@ fn search_files_with_name<P: AsRef<Path>, S: AsRef<str>>(root: P, name: S) -> Re
     Ok(files)
 }
 
-fn realtime_command_vec<S: AsRef<str>>(cmd_args: Vec<S>, failure_msg: S) -> Result<()> {
-    let mut cmd_args: Vec<&str> = cmd_args.iter().map(|s| s.as_ref()).collect();
-    let cmd = cmd_args.remove(0);
-
-    realtime_command(cmd, cmd_args, failure_msg.as_ref())
-}
-
-fn realtime_command_in_dir<P: AsRef<Path>, S: AsRef<str>>(
-    command: S,
-    dir: P,
-    args: Vec<S>,
-    failure_msg: S,
-) -> Result<()> {
-    println!("realtime_command_in_dir called on dir {:?}", dir.as_ref());
-
-    let command = command.as_ref();
-    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-    let failure_msg = failure_msg.as_ref();
-    let dir = dir.as_ref();
-
-    println!(
-        "Running command {} in realtime in dir {} with args {:?}",
-        command,
-        dir.to_string_lossy(),
-        &args,
-    );
-
-    let mut child = Command::new(command)
-        .args(&args)
-        .current_dir(dir)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .wrap_err_with(|| {
-            format!(
-                "Error spawning process {} with args {:?}: {failure_msg}",
-                command, args
-            )
-        })?;
-
-    let output = child.wait().wrap_err_with(|| {
-        format!(
-            "Failed getting exit status for process {} with args {:?}",
-            &command, &args
-        )
-    })?;
-
-    match output.code() {
-        Some(c) if c == 0 => return Ok(()),
-        Some(c) => {
-            return Err(eyre!(
-                "Process {} with args {:?} failed with return code {}",
-                &command,
-                &args,
-                c
-            ))
-        }
-        None => {
-            return Err(eyre!(
-                "Process {} with args {:?} was terminated by signal",
-                &command,
-                &args
-            ))
-        }
-    }
-}
-
-fn realtime_command<S: AsRef<str>>(command: S, args: Vec<S>, failure_msg: S) -> Result<()> {
-    let command = command.as_ref();
-    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
-    let failure_msg = failure_msg.as_ref();
-
-    debug!(
-        "Running command {} in realtime with args {:?}",
-        command, &args,
-    );
-
-    let mut child = Command::new(command)
-        .args(&args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .wrap_err_with(|| {
-            format!(
-                "Error spawning process {} with args {:?}: {failure_msg}",
-                command, args
-            )
-        })?;
-
-    let output = child.wait().wrap_err_with(|| {
-        format!(
-            "Failed getting exit status for process {} with args {:?}",
-            &command, &args
-        )
-    })?;
-
-    match output.code() {
-        Some(c) if c == 0 => return Ok(()),
-        Some(c) => {
-            return Err(eyre!(
-                "Process {} with args {:?} failed with return code {}",
-                &command,
-                &args,
-                c
-            ))
-        }
-        None => {
-            return Err(eyre!(
-                "Process {} with args {:?} was terminated by signal",
-                &command,
-                &args
-            ))
-        }
-    }
-}
-
 fn tag_file_content<P: AsRef<Path>, Tz: TimeZone>(path: P, timestamp: DateTime<Tz>) -> Result<()> {
     let path = path.as_ref();
 
@@ -446,15 +821,16 @@ fn path_is_file<P: AsRef<Path>>(path: P) -> Result<bool> {
 mod tests {
     use std::str::FromStr;
 
-    use assert_cmd::prelude::*;
     use chrono::Utc;
-    use predicates::prelude::*;
-    use tempfile::{tempdir, NamedTempFile};
+    use tempfile::{tempdir, NamedTempFile, TempDir};
 
     use super::*;
+    use crate::command::FakeRunner;
+    use crate::history::{read_history, Outcome};
+    use crate::platform::{Darwin, NixOs};
 
     fn dt() -> DateTime<Local> {
-        Local.with_ymd_and_hms(2023, 06, 16, 11, 12, 00).unwrap()
+        Local.with_ymd_and_hms(2023, 6, 16, 11, 12, 0).unwrap()
     }
 
     fn temp_file() -> NamedTempFile {
@@ -473,7 +849,7 @@ mod tests {
 
     #[test]
     fn test_tag_file_content() {
-        let dt: DateTime<Utc> = Utc.with_ymd_and_hms(2023, 06, 16, 11, 12, 00).unwrap();
+        let dt: DateTime<Utc> = Utc.with_ymd_and_hms(2023, 6, 16, 11, 12, 0).unwrap();
 
         let file = NamedTempFile::new().expect("Failed to create temporary file.");
 
@@ -587,4 +963,529 @@ mod tests {
             assert!(result.contains(path));
         }
     }
+
+    /// Creates config and install dirs under a temp dir, returning settings pointing at them
+    fn deploy_settings() -> (TempDir, Settings) {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config");
+        let install_path = dir.path().join("install");
+        fs::create_dir_all(&config_path).unwrap();
+        fs::create_dir_all(&install_path).unwrap();
+        fs::write(config_path.join("flake.nix"), "{ }").unwrap();
+
+        let mut settings = Settings::defaults().unwrap();
+        settings.config_path = config_path;
+        settings.install_path = install_path;
+        settings.state_dir = dir.path().join("state");
+        settings.system_profile = dir.path().join("system");
+        settings.current_system = dir.path().join("current-system");
+        (dir, settings)
+    }
+
+    fn concierge() -> String {
+        std::env::current_exe()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn snapshot(settings: &Settings) -> String {
+        format!(
+            "sudo {} sync --delete {} {}",
+            concierge(),
+            settings.install_path_string(),
+            settings.snapshot_path().to_string_lossy()
+        )
+    }
+
+    fn restore(settings: &Settings) -> String {
+        format!(
+            "sudo {} sync --delete {} {}",
+            concierge(),
+            settings.snapshot_path().to_string_lossy(),
+            settings.install_path_string()
+        )
+    }
+
+    fn sync_to_install(settings: &Settings) -> String {
+        format!(
+            "sudo {} sync '--filter=- .gitignore' '--filter=- .stfolder' '--filter=- .git' '--filter=- .concierge-backup' --delete {} {}",
+            concierge(),
+            settings.config_path_string(),
+            settings.install_path_string()
+        )
+    }
+
+    fn nixos() -> NixOs {
+        NixOs {
+            host: "host".to_string(),
+        }
+    }
+
+    fn macos() -> Darwin {
+        Darwin {
+            host: "host".to_string(),
+        }
+    }
+
+    fn build_nixos(settings: &Settings) -> String {
+        format!(
+            "cd {} && nixos-rebuild build --flake {}#host",
+            settings.state_dir.to_string_lossy(),
+            settings.install_path_string()
+        )
+    }
+
+    fn switch_nixos(settings: &Settings) -> String {
+        format!(
+            "sudo nixos-rebuild switch --flake {}#host",
+            settings.install_path_string()
+        )
+    }
+
+    fn build_macos(settings: &Settings) -> String {
+        format!(
+            "cd {} && darwin-rebuild build --flake {}#host",
+            settings.state_dir.to_string_lossy(),
+            settings.install_path_string()
+        )
+    }
+
+    fn switch_macos(settings: &Settings) -> String {
+        format!(
+            "darwin-rebuild switch --flake {}#host",
+            settings.install_path_string()
+        )
+    }
+
+    fn sync_locks_back(settings: &Settings) -> String {
+        format!(
+            "{} sync '--filter=+ *.lock' '--filter=+ */' '--filter=- *' --prune-empty-dirs {} {}",
+            concierge(),
+            settings.install_path_string(),
+            settings.config_path_string()
+        )
+    }
+
+    #[test]
+    fn should_run_nixos_deploy_commands() {
+        let (_dir, settings) = deploy_settings();
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                snapshot(&settings),
+                sync_to_install(&settings),
+                build_nixos(&settings),
+                switch_nixos(&settings),
+                sync_locks_back(&settings),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_run_macos_deploy_commands() {
+        let (_dir, settings) = deploy_settings();
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &macos(), &runner).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                snapshot(&settings),
+                sync_to_install(&settings),
+                "which darwin-rebuild".to_string(),
+                build_macos(&settings),
+                switch_macos(&settings),
+                sync_locks_back(&settings),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_only_build_in_build_mode() {
+        let (_dir, mut settings) = deploy_settings();
+        settings.mode(ActivationMode::Build);
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                snapshot(&settings),
+                sync_to_install(&settings),
+                build_nixos(&settings),
+            ]
+        );
+
+        settings.mode(ActivationMode::Boot);
+        let result =
+            deploy_nix_configuration(settings.clone(), "host".to_string(), &macos(), &runner);
+        assert!(format!("{:#}", result.unwrap_err()).contains("boot is not supported on darwin"));
+    }
+
+    #[test]
+    fn should_remove_snapshot_as_root_except_in_dry_run() {
+        let (_dir, mut settings) = deploy_settings();
+        fs::create_dir_all(settings.snapshot_path()).unwrap();
+        let remove = format!("sudo rm -rf {}", settings.snapshot_path().to_string_lossy());
+
+        let runner = FakeRunner::new();
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+        assert_eq!(runner.calls()[4], remove);
+
+        settings.dry_run();
+        let runner = FakeRunner::new();
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+        assert!(!runner.calls().contains(&remove));
+        assert!(settings.snapshot_path().exists());
+    }
+
+    #[test]
+    fn should_finish_activated_deployment_when_recording_it_fails() {
+        let (_dir, settings) = deploy_settings();
+        let link = settings.system_profile.with_file_name("system-41-link");
+        std::os::unix::fs::symlink("/nix/store/41-nixos-system", &link).unwrap();
+        std::os::unix::fs::symlink(&link, &settings.system_profile).unwrap();
+        fs::create_dir_all(settings.snapshot_path()).unwrap();
+        let runner = FakeRunner::new().fail("generations/41");
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+
+        assert!(runner.calls().iter().any(|c| c.contains("generations/41")));
+        assert!(runner.calls().contains(&format!(
+            "sudo rm -rf {}",
+            settings.snapshot_path().to_string_lossy()
+        )));
+        assert!(settings.manifest_path().exists());
+    }
+
+    #[test]
+    fn should_refuse_to_overwrite_edits_to_install_path_without_force() {
+        let (_dir, mut settings) = deploy_settings();
+        fs::create_dir_all(&settings.install_path).unwrap();
+        fs::write(settings.install_path.join("flake.nix"), "{}").unwrap();
+        write_manifest(&settings, &nixos()).unwrap();
+        fs::write(settings.install_path.join("flake.nix"), "{ edited }").unwrap();
+
+        let error = check_drift(&settings, false).unwrap_err();
+        assert!(error.to_string().contains("--force"), "{}", error);
+
+        // edits pulled back into a --rev worktree would be lost, so don't offer it
+        let mut at_rev = settings.clone();
+        at_rev.rev("v1".to_string());
+        let error = check_drift(&at_rev, true).unwrap_err();
+        assert!(error.to_string().contains("deploying v1"), "{}", error);
+
+        settings.force();
+        let runner = FakeRunner::new();
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+        assert_eq!(runner.calls()[1], sync_to_install(&settings));
+    }
+
+    #[test]
+    fn should_skip_deployment_when_nothing_changed() {
+        let (_dir, mut settings) = deploy_settings();
+        fs::write(settings.install_path.join("flake.nix"), "{ }").unwrap();
+        write_manifest(&settings, &nixos()).unwrap();
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+
+        settings.update();
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+        assert!(runner.calls().contains(&switch_nixos(&settings)));
+    }
+
+    #[test]
+    fn should_update_with_fallback_on_nixos() {
+        let (_dir, mut settings) = deploy_settings();
+        settings.update();
+        settings.fallback();
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                snapshot(&settings),
+                sync_to_install(&settings),
+                format!(
+                    "sudo nix flake update --fallback --flake {}",
+                    settings.install_path_string()
+                ),
+                build_nixos(&settings),
+                switch_nixos(&settings),
+                sync_locks_back(&settings),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_update_without_sudo_on_macos() {
+        let (_dir, mut settings) = deploy_settings();
+        settings.update();
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &macos(), &runner).unwrap();
+
+        assert_eq!(
+            runner.calls()[2],
+            format!(
+                "nix flake update --flake {}",
+                settings.install_path_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_update_single_input_in_config_dir_first() {
+        let (_dir, mut settings) = deploy_settings();
+        settings.update_input("nixpkgs".to_string());
+        let runner = FakeRunner::new();
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &macos(), &runner).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!(
+                    "cd {} && nix flake update nixpkgs",
+                    settings.config_path_string()
+                ),
+                snapshot(&settings),
+                sync_to_install(&settings),
+                "which darwin-rebuild".to_string(),
+                build_macos(&settings),
+                switch_macos(&settings),
+                sync_locks_back(&settings),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_show_closure_changes_before_switching() {
+        let (_dir, settings) = deploy_settings();
+        fs::create_dir_all(&settings.current_system).unwrap();
+        let runner = FakeRunner::new()
+            .respond("current-system", 0, "/nix/store/a-htop-3.2 1024\n")
+            .respond("result", 0, "/nix/store/b-htop-3.3 1024\n");
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner).unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(calls[2], build_nixos(&settings));
+        assert_eq!(
+            calls[3..5],
+            [
+                format!(
+                    "nix path-info --recursive --size {}",
+                    settings.current_system.to_string_lossy()
+                ),
+                format!(
+                    "nix path-info --recursive --size {}",
+                    settings.build_result_path().to_string_lossy()
+                ),
+            ]
+        );
+        assert_eq!(calls[5], switch_nixos(&settings));
+    }
+
+    #[test]
+    fn should_restore_install_path_when_activation_fails() {
+        let (_dir, settings) = deploy_settings();
+        let runner = FakeRunner::new().fail("nixos-rebuild switch");
+
+        let result =
+            deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner);
+
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("rolled back: restored"), "{}", error);
+        assert_eq!(
+            runner.calls(),
+            vec![
+                snapshot(&settings),
+                sync_to_install(&settings),
+                build_nixos(&settings),
+                switch_nixos(&settings),
+                restore(&settings),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_commit_and_push_changed_lock_with_commit_lock() {
+        let (dir, mut settings) = deploy_settings();
+        settings.commit_lock();
+        let lock = r#"{"nodes": {"root": {}}, "root": "root", "version": 7}"#;
+        fs::write(settings.lock_file(), lock).unwrap();
+
+        let bare_path = dir.path().join("remote.git");
+        git2::Repository::init_bare(&bare_path).unwrap();
+        let repo = git2::Repository::init(&settings.config_path).unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let deployed = repo
+            .commit(Some("HEAD"), &signature, &signature, "Init", &tree, &[])
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+        let mut remote = repo.remote("origin", bare_path.to_str().unwrap()).unwrap();
+        remote
+            .push(&[format!("refs/heads/{branch}:refs/heads/{branch}")], None)
+            .unwrap();
+        remote
+            .fetch(
+                &[format!("refs/heads/{branch}:refs/remotes/origin/{branch}")],
+                None,
+                None,
+            )
+            .unwrap();
+        repo.find_branch(&branch, git2::BranchType::Local)
+            .unwrap()
+            .set_upstream(Some(&format!("origin/{branch}")))
+            .unwrap();
+
+        fs::write(settings.config_path.join("wip.nix"), "{ }").unwrap();
+        let error = deploy_nix_configuration(
+            settings.clone(),
+            "host".to_string(),
+            &nixos(),
+            &FakeRunner::new(),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("uncommitted changes: wip.nix"),
+            "{:#}",
+            error
+        );
+        fs::remove_file(settings.config_path.join("wip.nix")).unwrap();
+
+        // as if the deployment relocked an input
+        fs::write(settings.lock_file(), lock.replace("7", "8")).unwrap();
+        deploy_nix_configuration(
+            settings.clone(),
+            "host".to_string(),
+            &nixos(),
+            &FakeRunner::new(),
+        )
+        .unwrap();
+
+        assert!(changed_files(&settings.config_path).unwrap().is_empty());
+        let pushed = git2::Repository::open_bare(&bare_path)
+            .unwrap()
+            .find_reference(&format!("refs/heads/{branch}"))
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .id();
+        assert_eq!(repo.head().unwrap().target(), Some(pushed));
+        let message = repo
+            .find_commit(pushed)
+            .unwrap()
+            .message()
+            .unwrap()
+            .to_string();
+        assert!(message.starts_with("Update flake.lock on host\n\nLock files:\n    flake.lock\n"));
+        let history = read_history(&settings).unwrap();
+        assert_eq!(history.last().unwrap().commit, Some(deployed.to_string()));
+    }
+
+    #[test]
+    fn should_record_deployments_in_history() {
+        let (_dir, mut settings) = deploy_settings();
+        fs::write(settings.config_path.join("flake.lock"), "{}").unwrap();
+        deploy_nix_configuration(
+            settings.clone(),
+            "host".to_string(),
+            &nixos(),
+            &FakeRunner::new(),
+        )
+        .unwrap();
+        settings.update();
+        let runner = FakeRunner::new().fail("nixos-rebuild switch");
+        assert!(
+            deploy_nix_configuration(settings.clone(), "host".to_string(), &nixos(), &runner)
+                .is_err()
+        );
+
+        let history = read_history(&settings).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].outcome, Outcome::Success);
+        assert_eq!(history[0].hostname, "host");
+        assert!(history[0].locks_before.contains_key("flake.lock"));
+        assert_eq!(history[1].outcome, Outcome::Failure);
+        assert_eq!(history[1].flags, vec!["--update"]);
+        assert_eq!(history[1].commands, runner.calls());
+        assert!(history[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("Failed to build and apply"));
+    }
+
+    #[test]
+    fn should_not_roll_back_unchanged_system_generation() {
+        let (_dir, mut settings) = deploy_settings();
+        settings.rollback_system();
+        let link = settings.system_profile.with_file_name("system-41-link");
+        std::os::unix::fs::symlink("/nix/store/41-nixos-system", &link).unwrap();
+        std::os::unix::fs::symlink(&link, &settings.system_profile).unwrap();
+        let runner = FakeRunner::new().fail("darwin-rebuild switch --flake");
+
+        let result =
+            deploy_nix_configuration(settings.clone(), "host".to_string(), &macos(), &runner);
+
+        assert!(result.is_err());
+        assert_eq!(runner.calls().last().unwrap(), &restore(&settings));
+    }
+
+    #[test]
+    fn should_roll_back_system_when_generation_changed() {
+        let (_dir, mut settings) = deploy_settings();
+        settings.rollback_system();
+        let runner = FakeRunner::new();
+
+        let error = roll_back(
+            &settings,
+            &nixos(),
+            &runner,
+            None,
+            Some(41),
+            eyre!("activation failed"),
+        );
+
+        assert_eq!(
+            runner.calls(),
+            vec!["sudo nixos-rebuild switch --rollback".to_string()]
+        );
+        assert!(format!("{:#}", error).contains("rolled back the system generation"));
+    }
+
+    #[test]
+    fn should_bootstrap_nix_darwin_when_darwin_rebuild_is_missing() {
+        let (_dir, settings) = deploy_settings();
+        let runner = FakeRunner::new().fail("which darwin-rebuild");
+
+        deploy_nix_configuration(settings.clone(), "host".to_string(), &macos(), &runner).unwrap();
+
+        let calls = runner.calls();
+        let bootstrap = format!(
+            "nix run nix-darwin -- switch --flake {}#host",
+            settings.install_path_string()
+        );
+        assert!(calls.contains(&bootstrap), "{:?}", calls);
+        assert!(!calls.iter().any(|c| c.contains("darwin-rebuild build")));
+    }
 }
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, WrapErr};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::hash::hash_file;
+use crate::platform::{ActivationMode, Platform};
+use crate::settings::Settings;
+use crate::sync::{walk, EntryType, Filter};
+
+/// Content hashes of the files in the install path as the last deployment left them,
+/// with what that deployment activated
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<PathBuf, String>,
+    /// The flake configuration deployed, e.g. `nixosConfigurations.web1`
+    #[serde(default)]
+    pub configuration: Option<String>,
+    #[serde(default)]
+    pub mode: Option<ActivationMode>,
+    /// The system generation the deployment left current
+    #[serde(default)]
+    pub generation: Option<u32>,
+}
+
+impl Manifest {
+    /// Hashes every file under `root` that `filter` doesn't exclude
+    pub fn build(root: &Path, filter: &Filter) -> Result<Manifest> {
+        let mut files = BTreeMap::new();
+        for (relative, entry_type) in walk(root, filter)? {
+            if entry_type != EntryType::File {
+                continue;
+            }
+            let path = root.join(&relative);
+            let hash = hash_file(&path).wrap_err_with(|| format!("Failed to hash {:?}", path))?;
+            files.insert(relative, hash);
+        }
+        Ok(Manifest {
+            files,
+            ..Default::default()
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Option<Manifest>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents =
+            fs::read_to_string(path).wrap_err_with(|| format!("Failed to read {:?}", path))?;
+        Ok(Some(serde_json::from_str(&contents).wrap_err_with(
+            || format!("Invalid install manifest {:?}", path),
+        )?))
+    }
+
+    /// Files that were changed, added or removed going from `self` to `current`
+    pub fn diff(&self, current: &Manifest) -> Vec<Drift> {
+        let mut drift = vec![];
+        for (path, hash) in &self.files {
+            match current.files.get(path) {
+                None => drift.push(Drift {
+                    path: path.clone(),
+                    kind: DriftKind::Removed,
+                }),
+                Some(current_hash) if current_hash != hash => drift.push(Drift {
+                    path: path.clone(),
+                    kind: DriftKind::Modified,
+                }),
+                Some(_) => {}
+            }
+        }
+        for path in current.files.keys() {
+            if !self.files.contains_key(path) {
+                drift.push(Drift {
+                    path: path.clone(),
+                    kind: DriftKind::Added,
+                });
+            }
+        }
+        drift.sort_by(|a, b| a.path.cmp(&b.path));
+        drift
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriftKind {
+    Modified,
+    Added,
+    Removed,
+}
+
+/// A file in the install path edited outside of concierge since the last deployment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Drift {
+    pub path: PathBuf,
+    pub kind: DriftKind,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            DriftKind::Modified => "modified",
+            DriftKind::Added => "added",
+            DriftKind::Removed => "removed",
+        };
+        write!(f, "{:<8}  {}", kind, self.path.to_string_lossy())
+    }
+}
+
+fn manifest_filter(settings: &Settings) -> Result<Filter> {
+    Filter::excluding(&settings.sync_exclusions)
+}
+
+/// The flake output and attribute `platform` deploys, e.g. `nixosConfigurations.web1`
+pub fn configuration_name(platform: &dyn Platform) -> String {
+    let (output, attr) = platform.configuration();
+    format!("{}.{}", output, attr)
+}
+
+/// Records the install path's files so the next deployment can tell if they were
+/// edited, or if there is anything to deploy at all
+pub fn write_manifest(settings: &Settings, platform: &dyn Platform) -> Result<()> {
+    write_manifest_as(
+        settings,
+        platform,
+        settings.mode,
+        platform.current_generation(settings),
+    )
+}
+
+/// Records the install path's files as activated with `mode`, leaving `generation` current
+pub fn write_manifest_as(
+    settings: &Settings,
+    platform: &dyn Platform,
+    mode: ActivationMode,
+    generation: Option<u32>,
+) -> Result<()> {
+    let manifest = Manifest {
+        configuration: Some(configuration_name(platform)),
+        mode: Some(mode),
+        generation,
+        ..Manifest::build(&settings.install_path, &manifest_filter(settings)?)?
+    };
+    let path = settings.manifest_path();
+    debug!(
+        "Recording {} installed files in {:?}",
+        manifest.files.len(),
+        path
+    );
+    fs::create_dir_all(&settings.state_dir)
+        .wrap_err_with(|| format!("Failed to create state dir {:?}", settings.state_dir))?;
+    fs::write(&path, serde_json::to_string_pretty(&manifest)?)
+        .wrap_err_with(|| format!("Failed to write {:?}", path))
+}
+
+/// Files in the install path that changed since the last deployment. Without a
+/// manifest from a previous deployment there is nothing to compare with.
+pub fn detect_drift(settings: &Settings) -> Result<Vec<Drift>> {
+    let recorded = match Manifest::read(&settings.manifest_path())? {
+        Some(manifest) => manifest,
+        None => {
+            debug!("No install manifest, not checking for drift");
+            return Ok(vec![]);
+        }
+    };
+    if !settings.install_path.exists() {
+        return Ok(vec![]);
+    }
+    let current = Manifest::build(&settings.install_path, &manifest_filter(settings)?)?;
+    Ok(recorded.diff(&current))
+}
+
+/// Whether the last deployment already switched to this configuration from the same
+/// files, and the system is still on the generation it left, so deploying would do nothing
+pub fn already_deployed(settings: &Settings, platform: &dyn Platform) -> Result<bool> {
+    let deployed = match Manifest::read(&settings.manifest_path())? {
+        Some(manifest) => manifest,
+        None => return Ok(false),
+    };
+    if settings.mode != ActivationMode::Switch
+        || deployed.mode != Some(ActivationMode::Switch)
+        || deployed.configuration != Some(configuration_name(platform))
+        || deployed.generation != platform.current_generation(settings)
+    {
+        return Ok(false);
+    }
+    let config = Manifest::build(&settings.config_path, &manifest_filter(settings)?)?;
+    Ok(config.files == deployed.files)
+}
+
+/// Applies the drifted files to the config path, so deploying keeps the edits
+pub fn pull_back(settings: &Settings, drift: &[Drift]) -> Result<()> {
+    for d in drift {
+        let installed = settings.install_path.join(&d.path);
+        let config = settings.config_path.join(&d.path);
+        match d.kind {
+            DriftKind::Modified | DriftKind::Added => {
+                if let Some(parent) = config.parent() {
+                    fs::create_dir_all(parent)
+                        .wrap_err_with(|| format!("Failed to create dir {:?}", parent))?;
+                }
+                fs::copy(&installed, &config).wrap_err_with(|| {
+                    format!("Failed to copy {:?} back to {:?}", installed, config)
+                })?;
+            }
+            DriftKind::Removed => {
+                if config.exists() {
+                    fs::remove_file(&config)
+                        .wrap_err_with(|| format!("Failed to remove {:?}", config))?;
+                }
+            }
+        }
+        println!("Pulled back {}", d);
+    }
+    Ok(())
+}
+
+pub fn render_drift(drift: &[Drift]) -> String {
+    drift
+        .iter()
+        .map(|d| format!("    {}", d))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::platform::NixOs;
+
+    fn nixos() -> NixOs {
+        NixOs {
+            host: "host".to_string(),
+        }
+    }
+
+    #[test]
+    fn should_detect_and_pull_back_edits_to_install_path() {
+        let dir = tempdir().unwrap();
+        let mut settings = Settings::defaults().unwrap();
+        settings.config_path = dir.path().join("config");
+        settings.install_path = dir.path().join("install");
+        settings.state_dir = dir.path().join("state");
+        for root in [&settings.config_path, &settings.install_path] {
+            fs::create_dir_all(root.join("hosts")).unwrap();
+            fs::write(root.join("flake.nix"), "{}").unwrap();
+            fs::write(root.join("hosts/web.nix"), "{ }").unwrap();
+            fs::create_dir_all(root.join(".git")).unwrap();
+            fs::write(root.join(".git/HEAD"), "main").unwrap();
+        }
+        assert!(detect_drift(&settings).unwrap().is_empty());
+        write_manifest(&settings, &nixos()).unwrap();
+        assert!(already_deployed(&settings, &nixos()).unwrap());
+
+        fs::write(settings.install_path.join("hosts/web.nix"), "{ x = 1; }").unwrap();
+        fs::write(settings.install_path.join("hosts/db.nix"), "{ }").unwrap();
+        fs::remove_file(settings.install_path.join("flake.nix")).unwrap();
+        fs::write(settings.install_path.join(".git/HEAD"), "other").unwrap();
+
+        let drift = detect_drift(&settings).unwrap();
+        assert_eq!(
+            render_drift(&drift),
+            [
+                "    removed   flake.nix",
+                "    added     hosts/db.nix",
+                "    modified  hosts/web.nix",
+            ]
+            .join("\n")
+        );
+
+        pull_back(&settings, &drift).unwrap();
+        assert!(!already_deployed(&settings, &nixos()).unwrap());
+        assert!(!settings.config_path.join("flake.nix").exists());
+        assert_eq!(
+            fs::read_to_string(settings.config_path.join("hosts/web.nix")).unwrap(),
+            "{ x = 1; }"
+        );
+        assert!(settings.config_path.join("hosts/db.nix").exists());
+    }
+}
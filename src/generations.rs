@@ -0,0 +1,437 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use eyre::{eyre, Result, WrapErr};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::command::CommandRunner;
+use crate::deploy::sync_command;
+use crate::drift::write_manifest_as;
+use crate::history::{config_commit, create_state_dir};
+use crate::platform::{ActivationMode, Platform};
+use crate::settings::Settings;
+use crate::sync::SyncOptions;
+
+/// A generation of a nix profile, i.e. a `<profile>-<number>-link` symlink
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Generation {
+    pub number: u32,
+    pub link: PathBuf,
+    pub store_path: PathBuf,
+    pub created: DateTime<Local>,
+    pub current: bool,
+}
+
+/// Links a system generation to the deployment that built it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentRecord {
+    pub generation: u32,
+    pub commit: Option<String>,
+    pub time: String,
+    pub snapshot: PathBuf,
+}
+
+/// Lists the generations of `profile`, oldest first
+pub fn list_generations(profile: &Path) -> Result<Vec<Generation>> {
+    let dir = profile
+        .parent()
+        .ok_or_else(|| eyre!("Profile {:?} has no parent dir", profile))?;
+    let name = profile
+        .file_name()
+        .ok_or_else(|| eyre!("Profile {:?} has no file name", profile))?
+        .to_string_lossy();
+    let current = fs::read_link(profile).ok();
+
+    let mut generations = vec![];
+    for entry in fs::read_dir(dir).wrap_err_with(|| format!("Failed to read dir {:?}", dir))? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let number = match file_name
+            .strip_prefix(&format!("{}-", name))
+            .and_then(|s| s.strip_suffix("-link"))
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            Some(number) => number,
+            None => continue,
+        };
+
+        let link = entry.path();
+        let metadata = fs::symlink_metadata(&link)?;
+        generations.push(Generation {
+            number,
+            store_path: fs::read_link(&link)
+                .wrap_err_with(|| format!("Failed to read generation link {:?}", link))?,
+            created: DateTime::from(metadata.modified()?),
+            current: current
+                .as_ref()
+                .is_some_and(|c| c.file_name() == link.file_name()),
+            link,
+        });
+    }
+
+    generations.sort_by_key(|g| g.number);
+    Ok(generations)
+}
+
+pub fn current_generation(profile: &Path) -> Result<Option<Generation>> {
+    Ok(list_generations(profile)?.into_iter().find(|g| g.current))
+}
+
+fn records_file(settings: &Settings) -> PathBuf {
+    settings.state_dir.join("generations.jsonl")
+}
+
+/// Where the install path as deployed for `generation` is kept
+pub fn generation_snapshot(settings: &Settings, generation: u32) -> PathBuf {
+    settings
+        .state_dir
+        .join("generations")
+        .join(generation.to_string())
+}
+
+pub fn read_records(settings: &Settings) -> Result<Vec<DeploymentRecord>> {
+    let path = records_file(settings);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read {:?}", path))?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| serde_json::from_str(l).wrap_err_with(|| format!("Invalid record in {:?}", path)))
+        .collect()
+}
+
+/// Records which config commit built the current system generation, and
+/// snapshots the install path so `rollback` can restore it later.
+pub fn record_deployment(settings: &Settings, runner: &dyn CommandRunner) -> Result<()> {
+    let generation = match current_generation(&settings.system_profile)? {
+        Some(generation) => generation,
+        None => {
+            warn!(
+                "No current generation of {:?}, not recording deployment",
+                settings.system_profile
+            );
+            return Ok(());
+        }
+    };
+
+    let snapshot = generation_snapshot(settings, generation.number);
+    runner
+        .run(&sync_command(
+            &settings.install_path,
+            &snapshot,
+            &SyncOptions {
+                delete: true,
+                ..Default::default()
+            },
+            false,
+        )?)
+        .wrap_err_with(|| format!("Failed to snapshot generation {}", generation.number))?;
+
+    let record = DeploymentRecord {
+        generation: generation.number,
+        commit: config_commit(settings),
+        time: Local::now().to_rfc3339(),
+        snapshot,
+    };
+    debug!("Recording deployment {:?}", record);
+
+    let path = records_file(settings);
+    fs::create_dir_all(&settings.state_dir)
+        .wrap_err_with(|| format!("Failed to create state dir {:?}", settings.state_dir))?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .wrap_err_with(|| format!("Failed to open {:?}", path))?;
+    writeln!(file, "{}", serde_json::to_string(&record)?)
+        .wrap_err_with(|| format!("Failed to write to {:?}", path))?;
+
+    prune_snapshots(settings)
+}
+
+/// Removes snapshots of generations that have since been garbage collected
+fn prune_snapshots(settings: &Settings) -> Result<()> {
+    let dir = settings.state_dir.join("generations");
+    if !dir.exists() {
+        return Ok(());
+    }
+    let live: Vec<String> = list_generations(&settings.system_profile)?
+        .iter()
+        .map(|g| g.number.to_string())
+        .collect();
+
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if !live.contains(&entry.file_name().to_string_lossy().into_owned()) {
+            debug!(
+                "Removing snapshot of collected generation {:?}",
+                entry.path()
+            );
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the system generations with the config commit that built each
+pub fn show_generations(settings: &Settings) -> Result<String> {
+    let generations = list_generations(&settings.system_profile)?;
+    let records = read_records(settings)?;
+
+    let lines: Vec<String> = generations
+        .iter()
+        .map(|g| {
+            let commit = records
+                .iter()
+                .rev()
+                .find(|r| r.generation == g.number)
+                .and_then(|r| r.commit.as_deref())
+                .map_or("-".to_string(), |c| c.chars().take(12).collect());
+            format!(
+                "{:>5}  {}  {:<12}{}",
+                g.number,
+                g.created.format("%Y-%m-%d %H:%M:%S"),
+                commit,
+                if g.current { "  (current)" } else { "" }
+            )
+        })
+        .collect();
+
+    Ok(lines.join("\n"))
+}
+
+/// Switches the system to generation `to`, or the one before the current
+/// generation, and restores the install path as it was deployed for it.
+pub fn rollback(
+    settings: &Settings,
+    platform: &dyn Platform,
+    runner: &dyn CommandRunner,
+    to: Option<u32>,
+) -> Result<()> {
+    let generations = list_generations(&settings.system_profile)?;
+    let current = generations
+        .iter()
+        .find(|g| g.current)
+        .ok_or_else(|| eyre!("No current generation of {:?}", settings.system_profile))?;
+
+    let target = match to {
+        Some(number) => generations
+            .iter()
+            .find(|g| g.number == number)
+            .ok_or_else(|| eyre!("Generation {} does not exist", number))?,
+        None => generations
+            .iter()
+            .rev()
+            .find(|g| g.number < current.number)
+            .ok_or_else(|| eyre!("No generation before current generation {}", current.number))?,
+    };
+
+    if target.number == current.number {
+        println!("Generation {} is already current.", target.number);
+        return Ok(());
+    }
+
+    println!(
+        "*** Rolling back from generation {} to {}",
+        current.number, target.number
+    );
+
+    if !settings.dry_run {
+        create_state_dir(settings, runner)?;
+    }
+    let commands = platform.switch_generation_commands(settings, target.number)?;
+    for cmd in commands {
+        runner
+            .run(&cmd)
+            .wrap_err_with(|| format!("Failed to switch to generation {}", target.number))?;
+    }
+
+    let snapshot = generation_snapshot(settings, target.number);
+    if snapshot.exists() {
+        runner
+            .run(&sync_command(
+                &snapshot,
+                &settings.install_path,
+                &SyncOptions {
+                    delete: true,
+                    ..Default::default()
+                },
+                true,
+            )?)
+            .wrap_err_with(|| format!("Failed to restore {:?}", settings.install_path))?;
+        if !settings.dry_run {
+            write_manifest_as(
+                settings,
+                platform,
+                ActivationMode::Switch,
+                Some(target.number),
+            )
+            .wrap_err_with(|| "Failed to record restored files")?;
+        }
+        println!(
+            "*** Restored {} as deployed for generation {}",
+            settings.install_path_string(),
+            target.number
+        );
+    } else {
+        println!(
+            "*** No config snapshot for generation {}, {} was left as is",
+            target.number,
+            settings.install_path_string()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+    use crate::command::FakeRunner;
+    use crate::drift::{detect_drift, write_manifest, Manifest};
+    use crate::platform::NixOs;
+
+    /// Creates a profile with generations 1, 2 and 4, with 4 current
+    fn profile_settings() -> (TempDir, Settings) {
+        let dir = tempdir().unwrap();
+        let profiles = dir.path().join("profiles");
+        fs::create_dir(&profiles).unwrap();
+        for n in [1, 2, 4] {
+            symlink(
+                format!("/nix/store/{n}-nixos-system"),
+                profiles.join(format!("system-{n}-link")),
+            )
+            .unwrap();
+        }
+        symlink("system-4-link", profiles.join("system")).unwrap();
+        symlink("/nix/store/other", profiles.join("other-1-link")).unwrap();
+
+        let mut settings = Settings::defaults().unwrap();
+        settings.system_profile = profiles.join("system");
+        settings.state_dir = dir.path().join("state");
+        settings.install_path = dir.path().join("install");
+        settings.config_path = dir.path().join("config");
+        (dir, settings)
+    }
+
+    #[test]
+    fn should_list_profile_generations() {
+        let (_dir, settings) = profile_settings();
+
+        let generations = list_generations(&settings.system_profile).unwrap();
+
+        assert_eq!(
+            generations.iter().map(|g| g.number).collect::<Vec<_>>(),
+            vec![1, 2, 4]
+        );
+        assert_eq!(
+            generations.iter().map(|g| g.current).collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+        assert_eq!(
+            generations[0].store_path,
+            PathBuf::from("/nix/store/1-nixos-system")
+        );
+    }
+
+    #[test]
+    fn should_record_deployment_for_current_generation() {
+        let (_dir, settings) = profile_settings();
+        fs::create_dir_all(settings.state_dir.join("generations/3")).unwrap();
+        let runner = FakeRunner::new();
+
+        record_deployment(&settings, &runner).unwrap();
+
+        let records = read_records(&settings).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].generation, 4);
+        assert_eq!(records[0].snapshot, generation_snapshot(&settings, 4));
+        assert!(!settings.state_dir.join("generations/3").exists());
+        let shown = show_generations(&settings).unwrap();
+        let last = shown.lines().last().unwrap();
+        assert!(last.starts_with("    4  "));
+        assert!(last.ends_with("(current)"));
+    }
+
+    #[test]
+    fn should_roll_back_to_previous_generation_and_restore_snapshot() {
+        let (_dir, settings) = profile_settings();
+        let snapshot = generation_snapshot(&settings, 2);
+        fs::create_dir_all(&snapshot).unwrap();
+        fs::create_dir_all(&settings.install_path).unwrap();
+        let runner = FakeRunner::new();
+
+        rollback(
+            &settings,
+            &NixOs {
+                host: "host".to_string(),
+            },
+            &runner,
+            None,
+        )
+        .unwrap();
+
+        let profile = settings.system_profile.to_string_lossy();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                format!("sudo nix-env --profile {profile} --switch-generation 2"),
+                format!("sudo {profile}/bin/switch-to-configuration switch"),
+                format!(
+                    "sudo {} sync --delete {} {}",
+                    std::env::current_exe().unwrap().to_string_lossy(),
+                    snapshot.to_string_lossy(),
+                    settings.install_path_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_record_restored_files_so_rollback_leaves_no_drift() {
+        let (_dir, settings) = profile_settings();
+        let platform = NixOs {
+            host: "host".to_string(),
+        };
+        fs::create_dir_all(&settings.install_path).unwrap();
+        fs::write(settings.install_path.join("flake.nix"), "generation 4").unwrap();
+        write_manifest(&settings, &platform).unwrap();
+        // the install path as the fake runner's restore of generation 2's snapshot leaves it
+        fs::create_dir_all(generation_snapshot(&settings, 2)).unwrap();
+        fs::write(settings.install_path.join("flake.nix"), "generation 2").unwrap();
+        assert!(!detect_drift(&settings).unwrap().is_empty());
+
+        rollback(&settings, &platform, &FakeRunner::new(), None).unwrap();
+
+        assert!(detect_drift(&settings).unwrap().is_empty());
+        let manifest = Manifest::read(&settings.manifest_path()).unwrap().unwrap();
+        assert_eq!(manifest.generation, Some(2));
+        assert_eq!(manifest.mode, Some(ActivationMode::Switch));
+    }
+
+    #[test]
+    fn should_refuse_rollback_to_missing_generation() {
+        let (_dir, settings) = profile_settings();
+        let runner = FakeRunner::new();
+
+        assert!(rollback(
+            &settings,
+            &NixOs {
+                host: "host".to_string()
+            },
+            &runner,
+            Some(3)
+        )
+        .is_err());
+        assert!(runner.calls().is_empty());
+    }
+}
@@ -1,8 +1,19 @@
+use std::fmt;
 use std::path::{Path, PathBuf};
 
-use eyre::{Result, WrapErr};
-use git2::{BranchType, Repository, StatusOptions};
+use eyre::{eyre, Result, WrapErr};
+use git2::build::CheckoutBuilder;
+use git2::build::RepoBuilder;
+use git2::{
+    BranchType, Commit, Config, Cred, CredentialType, ErrorCode, FetchOptions, Oid, PushOptions,
+    RemoteCallbacks, Repository, Signature, StashFlags, StatusOptions, WorktreeAddOptions,
+    WorktreePruneOptions,
+};
 use git_url_parse::normalize_url;
+use log::{debug, warn};
+use tempfile::TempDir;
+
+use crate::command::Cmd;
 
 /// Transforms git url with whatever transport into a generic URL
 /// Useful to compare that two remote git repos are the same even if
@@ -12,25 +23,24 @@ use git_url_parse::normalize_url;
 /// despite using different transports. They would both become `github.com/username/repo`.
 ///   - `git@github.com:username/repo.git`
 ///   - `https://github.com/username/repo`
+///
+/// Local `file://` URLs have no host and normalize to their path alone.
 fn normalize_git_url(url: &str) -> Option<String> {
-    let url = normalize_url(url).expect("unable to normalize git url");
-    let host = url
-        .host_str()
-        .expect("could not get git url host string")
-        .to_string();
+    let url = normalize_url(url).ok()?;
+    let host = url.host_str().unwrap_or_default().to_string();
     let path = url
         .path()
         .trim_end_matches(".git")
-        .trim_start_matches("/")
+        .trim_start_matches('/')
         .to_string();
     Some(format!("{host}/{path}"))
 }
 
 fn is_same_repo(a: &str, b: &str) -> bool {
-    let repo_a = normalize_git_url(a);
-    let repo_b = normalize_git_url(b);
-
-    repo_a == repo_b
+    match (normalize_git_url(a), normalize_git_url(b)) {
+        (Some(repo_a), Some(repo_b)) => repo_a == repo_b,
+        _ => false,
+    }
 }
 
 pub fn repo_has_remote(local_path: PathBuf, remote_url: &str) -> Result<bool> {
@@ -45,6 +55,19 @@ pub fn is_git_repo<P: AsRef<Path>>(path: P) -> bool {
     Repository::discover(path).is_ok()
 }
 
+/// Returns the id of the commit HEAD points to
+pub fn head_commit<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let repo = Repository::discover(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to get HEAD commit.", path))?;
+    let commit = repo
+        .head()
+        .wrap_err_with(|| format!("Failed to get HEAD for repo {:?}", path))?
+        .peel_to_commit()
+        .wrap_err_with(|| format!("Failed to get HEAD commit for repo {:?}", path))?;
+    Ok(commit.id().to_string())
+}
+
 fn get_repo_remote_urls(path: PathBuf) -> Result<Vec<String>> {
     let repo = Repository::open(path.clone())
         .wrap_err_with(|| format!("Failed to open local reto at {path:?}"))?;
@@ -54,7 +77,7 @@ fn get_repo_remote_urls(path: PathBuf) -> Result<Vec<String>> {
 
     let remote_urls: Vec<String> = remotes
         .iter()
-        .filter_map(|r| r)
+        .flatten()
         .filter_map(|n| repo.find_remote(n).ok())
         .filter_map(|r| r.url().map(|u| u.to_string()))
         .collect();
@@ -75,63 +98,762 @@ pub fn is_working_tree_clean<P: AsRef<Path>>(path: P) -> Result<bool> {
     Ok(statuses.is_empty())
 }
 
-pub enum RepoStatus {
-    Ahead,
-    Behind,
-    Same,
-    Complex,
+/// What HEAD points to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Head {
+    Branch(String),
+    /// A commit rather than a branch, e.g. after checking out a tag
+    Detached(Oid),
+    /// A branch with no commits yet, as in a freshly initialised repo
+    Unborn(String),
 }
 
-pub fn repo_status<P: AsRef<Path>, S: AsRef<str>>(path: P, branch_name: S) -> Result<RepoStatus> {
+impl fmt::Display for Head {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Head::Branch(name) => write!(f, "{}", name),
+            Head::Detached(oid) => write!(f, "detached HEAD at {:.12}", oid.to_string()),
+            Head::Unborn(name) => write!(f, "{} with no commits", name),
+        }
+    }
+}
+
+pub fn head_state<P: AsRef<Path>>(path: P) -> Result<Head> {
     let path = path.as_ref();
-    let branch_name = branch_name.as_ref();
     let repo = Repository::open(path)
-        .wrap_err_with(|| format!("Failed getting repo {:?} to check status.", path))?;
+        .wrap_err_with(|| format!("Failed to open repo {:?} to get HEAD.", path))?;
+    let head = match repo.head() {
+        Ok(head) => head,
+        Err(e) if e.code() == ErrorCode::UnbornBranch => {
+            // HEAD names the branch even though the branch doesn't exist yet
+            let target = repo
+                .find_reference("HEAD")?
+                .symbolic_target()
+                .map(|t| t.trim_start_matches("refs/heads/").to_string())
+                .unwrap_or_default();
+            return Ok(Head::Unborn(target));
+        }
+        Err(e) => {
+            return Err(e).wrap_err_with(|| format!("Failed to get HEAD for repo {:?}", path))
+        }
+    };
+    if repo.head_detached()? {
+        let oid = head
+            .target()
+            .ok_or_else(|| eyre!("Detached HEAD of repo {:?} has no target", path))?;
+        return Ok(Head::Detached(oid));
+    }
+    head.shorthand()
+        .map(|s| Head::Branch(s.to_string()))
+        .ok_or_else(|| eyre!("Branch name of repo {:?} is not valid UTF-8", path))
+}
 
-    let mut remote = repo
-        .find_remote("origin")
-        .wrap_err_with(|| format!("Failed to get remote 'origin' for repo {:?}", path))?;
+/// Returns the short name of the branch HEAD currently points to.
+pub fn current_branch<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    match head_state(path)? {
+        Head::Branch(name) => Ok(name),
+        head => Err(eyre!(
+            "HEAD of repo {:?} is not a branch with commits: {}",
+            path,
+            head
+        )),
+    }
+}
+
+/// The remote branch a local branch tracks, from its `branch.<name>.remote` and `.merge` config
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    pub remote: String,
+    pub branch: String,
+}
+
+impl Upstream {
+    /// Name of the remote-tracking branch, e.g. `origin/main`
+    pub fn tracking_branch(&self) -> String {
+        format!("{}/{}", self.remote, self.branch)
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tracking_branch())
+    }
+}
+
+/// How HEAD's branch compares with its upstream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepoStatus {
+    pub branch: String,
+    pub upstream: Upstream,
+    /// Local commits the upstream doesn't have
+    pub ahead: usize,
+    /// Upstream commits the local branch doesn't have
+    pub behind: usize,
+}
+
+impl RepoStatus {
+    pub fn is_diverged(&self) -> bool {
+        self.ahead > 0 && self.behind > 0
+    }
+}
+
+impl fmt::Display for RepoStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.ahead, self.behind) {
+            (0, 0) => write!(f, "up to date with {}", self.upstream),
+            (ahead, 0) => write!(f, "{} ahead of {}", ahead, self.upstream),
+            (0, behind) => write!(f, "{} behind {}", behind, self.upstream),
+            (ahead, behind) => write!(f, "{} ahead, {} behind {}", ahead, behind, self.upstream),
+        }
+    }
+}
+
+/// How to authenticate with remotes that ask for credentials. SSH remotes get the
+/// SSH agent then each key file, HTTPS remotes get git's credential helpers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GitAuth {
+    /// Whether an SSH agent is running, i.e. `SSH_AUTH_SOCK` is set
+    pub ssh_agent: bool,
+    /// Private keys to try, in order, skipping any that don't exist
+    pub ssh_keys: Vec<PathBuf>,
+}
+
+impl GitAuth {
+    /// Callbacks that answer credential requests for fetches and pushes. Credential
+    /// helpers come from `repo`'s config, as `git` reads them, or the global config
+    /// without a repo.
+    pub fn callbacks(&self, repo: Option<&Repository>) -> RemoteCallbacks<'_> {
+        let config = match repo {
+            Some(repo) => repo.config().ok(),
+            None => None,
+        };
+        let mut credentials = Credentials::new(self, config);
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks
+            .credentials(move |url, username, allowed| credentials.next(url, username, allowed));
+        callbacks
+    }
+
+    pub fn fetch_options(&self, repo: Option<&Repository>) -> FetchOptions<'_> {
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(self.callbacks(repo));
+        options
+    }
+}
+
+/// A way of authenticating, each tried once per operation
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Attempt {
+    SshAgent,
+    SshKey(PathBuf),
+    CredentialHelper,
+}
+
+impl fmt::Display for Attempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attempt::SshAgent => write!(f, "the SSH agent"),
+            Attempt::SshKey(path) => write!(f, "key {:?}", path),
+            Attempt::CredentialHelper => write!(f, "git credential helpers"),
+        }
+    }
+}
+
+/// libgit2 asks for credentials again each time the last ones were rejected, so this
+/// hands out the next untried attempt until there are none left
+struct Credentials<'a> {
+    auth: &'a GitAuth,
+    /// Where credential helpers are configured, the global config if `None`
+    config: Option<Config>,
+    tried: Vec<Attempt>,
+}
+
+impl<'a> Credentials<'a> {
+    fn new(auth: &'a GitAuth, config: Option<Config>) -> Credentials<'a> {
+        Credentials {
+            auth,
+            config,
+            tried: vec![],
+        }
+    }
+
+    fn attempts(&self, allowed: CredentialType) -> Vec<Attempt> {
+        let mut attempts = vec![];
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if self.auth.ssh_agent {
+                attempts.push(Attempt::SshAgent);
+            }
+            attempts.extend(
+                self.auth
+                    .ssh_keys
+                    .iter()
+                    .filter(|k| k.exists())
+                    .map(|k| Attempt::SshKey(k.clone())),
+            );
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            attempts.push(Attempt::CredentialHelper);
+        }
+        attempts
+    }
+
+    fn next(
+        &mut self,
+        url: &str,
+        username: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        // SSH asks for the user name first if the URL has none
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username.unwrap_or("git"));
+        }
+        let ssh_user = username.unwrap_or("git");
+        for attempt in self.attempts(allowed) {
+            if self.tried.contains(&attempt) {
+                continue;
+            }
+            self.tried.push(attempt.clone());
+            let cred = match &attempt {
+                Attempt::SshAgent => Cred::ssh_key_from_agent(ssh_user),
+                Attempt::SshKey(path) => Cred::ssh_key(ssh_user, None, path, None),
+                Attempt::CredentialHelper => match &self.config {
+                    Some(config) => Cred::credential_helper(config, url, username),
+                    None => Config::open_default()
+                        .and_then(|config| Cred::credential_helper(&config, url, username)),
+                },
+            };
+            match cred {
+                Ok(cred) => {
+                    debug!("Authenticating with {} using {}", url, attempt);
+                    return Ok(cred);
+                }
+                Err(e) => debug!("Not authenticating using {}: {}", attempt, e),
+            }
+        }
+        Err(git2::Error::from_str(&self.failure(url)))
+    }
+
+    fn failure(&self, url: &str) -> String {
+        if self.tried.is_empty() {
+            return format!(
+                "Authentication failed for {}, no credentials were available. Start an SSH agent, set ssh_key, or configure a git credential helper.",
+                url
+            );
+        }
+        format!(
+            "Authentication failed for {}, tried {}",
+            url,
+            self.tried
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Clones `url` into `path`, authenticating with `auth`
+pub fn clone_repo<P: AsRef<Path>>(url: &str, path: P, auth: &GitAuth) -> Result<Repository> {
+    let path = path.as_ref();
+    RepoBuilder::new()
+        .fetch_options(auth.fetch_options(None))
+        .clone(url, path)
+        .wrap_err_with(|| format!("Failed cloning repository {:?} to {:?}", url, path))
+}
+
+fn branch_upstream(repo: &Repository, path: &Path, branch_name: &str) -> Result<Upstream> {
+    let refname = format!("refs/heads/{}", branch_name);
+    let missing = || {
+        eyre!(
+            "Branch {} of repo {:?} has no upstream, set one with `git branch --set-upstream-to`",
+            branch_name,
+            path
+        )
+    };
+    let remote = repo
+        .branch_upstream_remote(&refname)
+        .map_err(|_| missing())?;
+    let merge = repo
+        .config()
+        .and_then(|c| c.get_string(&format!("branch.{}.merge", branch_name)))
+        .map_err(|_| missing())?;
+    let remote = remote
+        .as_str()
+        .ok_or_else(|| eyre!("Upstream remote of {} is not valid UTF-8", branch_name))?;
+    Ok(Upstream {
+        remote: remote.to_string(),
+        branch: merge.trim_start_matches("refs/heads/").to_string(),
+    })
+}
+
+/// Fetches the upstream of HEAD's branch and counts how far apart the two are.
+/// A detached HEAD or a branch without commits or an upstream is an error.
+pub fn repo_status<P: AsRef<Path>>(path: P, auth: &GitAuth) -> Result<RepoStatus> {
+    let path = path.as_ref();
+    let branch_name = match head_state(path)? {
+        Head::Branch(name) => name,
+        head @ Head::Detached(_) => {
+            return Err(eyre!(
+                "Repo {:?} is at {}, check out a branch to compare it with its upstream",
+                path,
+                head
+            ))
+        }
+        Head::Unborn(name) => {
+            return Err(eyre!(
+                "Branch {} of repo {:?} has no commits to compare with its upstream",
+                name,
+                path
+            ))
+        }
+    };
+    let repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed getting repo {:?} to check status.", path))?;
+    let upstream = branch_upstream(&repo, path, &branch_name)?;
 
+    let mut remote = repo.find_remote(&upstream.remote).wrap_err_with(|| {
+        format!(
+            "Failed to get remote '{}' for repo {:?}",
+            upstream.remote, path
+        )
+    })?;
     remote
         .fetch(
             &[format!(
-                "refs/heads/{}:refs/remotes/origin/{}",
-                branch_name, branch_name
+                "refs/heads/{}:refs/remotes/{}",
+                upstream.branch,
+                upstream.tracking_branch()
             )],
-            None,
+            Some(&mut auth.fetch_options(Some(&repo))),
             None,
         )
         .wrap_err_with(|| format!("Failed to fetch updates for repo {:?}", path))?;
 
     let local_commit = repo
-        .find_branch(branch_name, BranchType::Local)
+        .find_branch(&branch_name, BranchType::Local)
         .wrap_err_with(|| format!("Failed to get local branch {}", branch_name))?
         .get()
         .peel_to_commit()
-        .wrap_err_with(|| format!("Failed to get latest commit."))?
+        .wrap_err_with(|| "Failed to get latest commit.")?
         .id();
 
-    let remote_branch_name = format!("origin/{}", branch_name);
     let remote_commit = repo
-        .find_reference(&remote_branch_name)
-        .wrap_err_with(|| format!("Failed to find reference {remote_branch_name}"))?
+        .find_branch(&upstream.tracking_branch(), BranchType::Remote)
+        .wrap_err_with(|| format!("Failed to find remote branch {}", upstream))?
+        .get()
         .peel_to_commit()
-        .wrap_err_with(|| format!("Failed to get latest remote commit."))?
+        .wrap_err_with(|| "Failed to get latest remote commit.")?
         .id();
 
     let (ahead, behind) = repo
         .graph_ahead_behind(local_commit, remote_commit)
         .wrap_err_with(|| "Failed to get graph ahead behind.")?;
 
-    if ahead > 0 && behind == 0 {
-        Ok(RepoStatus::Ahead)
-    } else if behind > 0 && ahead == 0 {
-        Ok(RepoStatus::Behind)
-    } else if ahead == 0 && behind == 0 {
-        Ok(RepoStatus::Same)
-    } else {
-        Ok(RepoStatus::Complex)
+    Ok(RepoStatus {
+        branch: branch_name,
+        upstream,
+        ahead,
+        behind,
+    })
+}
+
+/// Fast-forwards the local branch to its already fetched upstream.
+/// Returns an error rather than merging if a fast-forward is not possible.
+pub fn pull_fast_forward<P: AsRef<Path>>(path: P, status: &RepoStatus) -> Result<()> {
+    let path = path.as_ref();
+    let branch_name = status.branch.as_str();
+    let repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to pull.", path))?;
+
+    let remote_branch_name = status.upstream.tracking_branch();
+    let remote_ref = repo
+        .find_branch(&remote_branch_name, BranchType::Remote)
+        .wrap_err_with(|| format!("Failed to find remote branch {remote_branch_name}"))?
+        .into_reference();
+    let remote_commit = repo
+        .reference_to_annotated_commit(&remote_ref)
+        .wrap_err_with(|| format!("Failed to resolve {remote_branch_name} to a commit"))?;
+
+    let (analysis, _) = repo
+        .merge_analysis(&[&remote_commit])
+        .wrap_err_with(|| format!("Failed merge analysis against {remote_branch_name}"))?;
+
+    if analysis.is_up_to_date() {
+        return Ok(());
     }
+
+    if !analysis.is_fast_forward() {
+        return Err(eyre!(
+            "Cannot fast-forward {} to {} in repo {:?}",
+            branch_name,
+            remote_branch_name,
+            path
+        ));
+    }
+
+    // update the working tree first so a failed checkout leaves the branch untouched
+    let target = repo
+        .find_object(remote_commit.id(), None)
+        .wrap_err_with(|| format!("Failed to find commit for {remote_branch_name}"))?;
+    repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe()))
+        .wrap_err_with(|| format!("Failed to check out {remote_branch_name} in {:?}", path))?;
+
+    let mut local_ref = repo
+        .find_branch(branch_name, BranchType::Local)
+        .wrap_err_with(|| format!("Failed to get local branch {}", branch_name))?
+        .into_reference();
+    local_ref
+        .set_target(
+            remote_commit.id(),
+            &format!("concierge: fast-forward to {remote_branch_name}"),
+        )
+        .wrap_err_with(|| format!("Failed to fast-forward branch {}", branch_name))?;
+
+    Ok(())
+}
+
+/// Returns paths (relative to the repo root) of files with uncommitted changes
+/// whose file name matches `file_name`.
+pub fn changed_files_named<P: AsRef<Path>, S: AsRef<str>>(
+    path: P,
+    file_name: S,
+) -> Result<Vec<PathBuf>> {
+    let file_name = file_name.as_ref();
+    Ok(changed_files(path)?
+        .into_iter()
+        .filter(|p| p.file_name().is_some_and(|n| n == file_name))
+        .collect())
+}
+
+/// Paths relative to the repo root that are modified, added, deleted or untracked
+pub fn changed_files<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let repo = Repository::open(path)?;
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    let statuses = repo
+        .statuses(Some(&mut opts))
+        .wrap_err_with(|| format!("Failed getting statuses for repo {:?}", path))?;
+
+    Ok(statuses
+        .iter()
+        .filter_map(|s| s.path().map(PathBuf::from))
+        .collect())
+}
+
+/// Stashes uncommitted changes, untracked files included, returning `false` if
+/// there was nothing to stash
+pub fn stash_changes<P: AsRef<Path>>(path: P, message: &str) -> Result<bool> {
+    let path = path.as_ref();
+    let mut repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to stash.", path))?;
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("concierge", "concierge@localhost"))?;
+    match repo.stash_save(&signature, message, Some(StashFlags::INCLUDE_UNTRACKED)) {
+        Ok(_) => Ok(true),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(e).wrap_err_with(|| format!("Failed to stash changes in repo {:?}", path)),
+    }
+}
+
+/// Applies the latest stash and drops it. If it doesn't apply cleanly it is kept.
+pub fn pop_stash<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mut repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to pop stash.", path))?;
+    repo.stash_pop(0, None)
+        .wrap_err_with(|| format!("Failed to pop stash in repo {:?}", path))
+}
+
+/// How the repo's git config asks for commits to be signed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommitSigning {
+    /// `gpg.format` openpgp, with `user.signingkey` or gpg's default key
+    Gpg { key: Option<String> },
+    /// `gpg.format` ssh, with `user.signingkey` the path of the private key
+    Ssh { key: PathBuf },
+}
+
+impl CommitSigning {
+    /// Command that writes a detached signature of `file` to `signature_file(file)`
+    pub fn command(&self, file: &Path) -> Cmd {
+        let file = file.to_string_lossy();
+        match self {
+            CommitSigning::Gpg { key } => {
+                let mut cmd = Cmd::new("gpg").args(["--batch", "--yes", "--armor"]);
+                if let Some(key) = key {
+                    cmd = cmd.args(["--local-user", key]);
+                }
+                cmd.args(["--output", &format!("{}.asc", file), "--detach-sign", &file])
+            }
+            CommitSigning::Ssh { key } => Cmd::new("ssh-keygen").args([
+                "-Y",
+                "sign",
+                "-n",
+                "git",
+                "-f",
+                &key.to_string_lossy(),
+                &file,
+            ]),
+        }
+    }
+
+    pub fn signature_file(&self, file: &Path) -> PathBuf {
+        let extension = match self {
+            CommitSigning::Gpg { .. } => "asc",
+            CommitSigning::Ssh { .. } => "sig",
+        };
+        let mut name = file.as_os_str().to_owned();
+        name.push(format!(".{}", extension));
+        PathBuf::from(name)
+    }
+}
+
+/// Reads `commit.gpgsign`, `gpg.format` and `user.signingkey`, `None` if commits aren't signed
+pub fn commit_signing<P: AsRef<Path>>(path: P) -> Result<Option<CommitSigning>> {
+    let path = path.as_ref();
+    let config = Repository::open(path)
+        .and_then(|repo| repo.config())
+        .wrap_err_with(|| format!("Failed to read git config of repo {:?}", path))?;
+    if !config.get_bool("commit.gpgsign").unwrap_or(false) {
+        return Ok(None);
+    }
+    let key = config.get_string("user.signingkey").ok();
+    match config.get_string("gpg.format").as_deref() {
+        Ok("ssh") => {
+            let key = key.ok_or_else(|| {
+                eyre!("Commits are signed with SSH but user.signingkey is not set")
+            })?;
+            Ok(Some(CommitSigning::Ssh {
+                key: PathBuf::from(shellexpand::tilde(&key).into_owned()),
+            }))
+        }
+        Ok("openpgp") | Err(_) => Ok(Some(CommitSigning::Gpg { key })),
+        Ok(format) => Err(eyre!(
+            "Signing commits with gpg.format {} is not supported",
+            format
+        )),
+    }
+}
+
+/// Resolves a commit, tag or branch, trying the remotes' branches of that name if
+/// there is no local one
+fn resolve_rev<'r>(repo: &'r Repository, rev: &str) -> Result<Commit<'r>> {
+    let object = repo.revparse_single(rev).or_else(|e| {
+        repo.remotes()?
+            .iter()
+            .flatten()
+            .find_map(|remote| repo.revparse_single(&format!("{}/{}", remote, rev)).ok())
+            .ok_or(e)
+    });
+    object
+        .and_then(|o| o.peel_to_commit())
+        .wrap_err_with(|| format!("Unknown revision {:?}", rev))
+}
+
+/// A revision of a repo checked out in a temporary git worktree, which is removed
+/// along with its dir when dropped
+pub struct TempWorktree {
+    repo_path: PathBuf,
+    name: String,
+    path: PathBuf,
+    pub commit: Oid,
+    // removed after the worktree is pruned
+    _dir: TempDir,
+}
+
+impl TempWorktree {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempWorktree {
+    fn drop(&mut self) {
+        let pruned = Repository::open(&self.repo_path)
+            .and_then(|repo| repo.find_worktree(&self.name))
+            .and_then(|worktree| {
+                worktree.prune(Some(
+                    WorktreePruneOptions::new().valid(true).working_tree(true),
+                ))
+            });
+        if let Err(e) = pruned {
+            warn!("Failed to remove worktree {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Checks `rev` out into a temporary worktree of the repo at `path`, with a detached
+/// HEAD so no branch is tied to it. The repo's own working tree is left untouched.
+pub fn checkout_worktree<P: AsRef<Path>>(path: P, rev: &str) -> Result<TempWorktree> {
+    let path = path.as_ref();
+    let repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to check out {}", path, rev))?;
+    let commit = resolve_rev(&repo, rev)?;
+
+    let dir = tempfile::Builder::new()
+        .prefix("concierge-rev-")
+        .tempdir()
+        .wrap_err_with(|| "Failed to create dir for worktree")?;
+    let name = dir
+        .path()
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| eyre!("Temporary dir {:?} has no name", dir.path()))?;
+    let worktree_path = dir.path().join("config");
+
+    // libgit2 checks worktrees out from a branch, which is only needed until HEAD is detached
+    let mut branch = repo
+        .branch(&name, &commit, false)
+        .wrap_err_with(|| format!("Failed to create branch for {}", rev))?;
+    let added = repo.worktree(
+        &name,
+        &worktree_path,
+        Some(WorktreeAddOptions::new().reference(Some(branch.get()))),
+    );
+    let added = match added {
+        Ok(added) => added,
+        Err(e) => {
+            let _ = branch.delete();
+            return Err(e)
+                .wrap_err_with(|| format!("Failed to check out {} into {:?}", rev, worktree_path));
+        }
+    };
+    // from here on, dropping the worktree removes it
+    let worktree = TempWorktree {
+        repo_path: path.to_path_buf(),
+        name,
+        path: worktree_path,
+        commit: commit.id(),
+        _dir: dir,
+    };
+    Repository::open_from_worktree(&added)
+        .and_then(|r| r.set_head_detached(commit.id()))
+        .wrap_err_with(|| format!("Failed to detach HEAD of worktree {:?}", worktree.path))?;
+    branch
+        .delete()
+        .wrap_err_with(|| format!("Failed to remove branch for {}", rev))?;
+    Ok(worktree)
+}
+
+/// Returns a detached signature of a commit's contents
+pub type SignCommit<'a> = &'a dyn Fn(&str) -> Result<String>;
+
+/// Stages only the given paths and commits them on top of HEAD.
+/// Uses the repo's configured signature, falling back to a generic concierge one.
+/// With `sign`, the commit carries the signature it returns for the commit's contents.
+pub fn commit_files<P: AsRef<Path>, S: AsRef<str>>(
+    path: P,
+    files: &[PathBuf],
+    message: S,
+    sign: Option<SignCommit>,
+) -> Result<Oid> {
+    let path = path.as_ref();
+    let repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to commit.", path))?;
+
+    let mut index = repo.index().wrap_err_with(|| "Failed to get repo index")?;
+    for file in files {
+        // deleted files are staged as removals
+        let staged = if path.join(file).exists() {
+            index.add_path(file)
+        } else {
+            index.remove_path(file)
+        };
+        staged.wrap_err_with(|| format!("Failed to stage {:?}", file))?;
+    }
+    index
+        .write()
+        .wrap_err_with(|| "Failed to write repo index")?;
+
+    let tree_id = index
+        .write_tree()
+        .wrap_err_with(|| "Failed to write tree")?;
+    let tree = repo.find_tree(tree_id)?;
+    let parent = repo
+        .head()
+        .wrap_err_with(|| "Failed to get HEAD")?
+        .peel_to_commit()
+        .wrap_err_with(|| "Failed to get HEAD commit")?;
+
+    let signature = repo
+        .signature()
+        .or_else(|_| Signature::now("concierge", "concierge@localhost"))
+        .wrap_err_with(|| "Failed to create commit signature")?;
+
+    let sign = match sign {
+        Some(sign) => sign,
+        None => {
+            return repo
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    message.as_ref(),
+                    &tree,
+                    &[&parent],
+                )
+                .wrap_err_with(|| format!("Failed to commit {:?} in repo {:?}", files, path))
+        }
+    };
+
+    let buffer = repo
+        .commit_create_buffer(&signature, &signature, message.as_ref(), &tree, &[&parent])
+        .wrap_err_with(|| format!("Failed to create commit of {:?}", files))?;
+    let content = buffer
+        .as_str()
+        .ok_or_else(|| eyre!("Commit of {:?} is not valid UTF-8", files))?;
+    let commit_signature = sign(content).wrap_err_with(|| "Failed to sign commit")?;
+    let oid = repo
+        .commit_signed(content, &commit_signature, None)
+        .wrap_err_with(|| format!("Failed to commit {:?} in repo {:?}", files, path))?;
+    repo.head()?
+        .set_target(oid, message.as_ref())
+        .wrap_err_with(|| format!("Failed to move HEAD of repo {:?} to the commit", path))?;
+    Ok(oid)
+}
+
+/// Pushes the local branch to its upstream.
+pub fn push<P: AsRef<Path>>(path: P, status: &RepoStatus, auth: &GitAuth) -> Result<()> {
+    let path = path.as_ref();
+    let repo = Repository::open(path)
+        .wrap_err_with(|| format!("Failed to open repo {:?} to push.", path))?;
+    let mut remote = repo
+        .find_remote(&status.upstream.remote)
+        .wrap_err_with(|| {
+            format!(
+                "Failed to get remote '{}' for repo {:?}",
+                status.upstream.remote, path
+            )
+        })?;
+
+    // libgit2 reports rejected refs through this callback rather than as an error from `push`
+    let mut callbacks = auth.callbacks(Some(&repo));
+    callbacks.push_update_reference(|refname, status| match status {
+        Some(message) => Err(git2::Error::from_str(&format!(
+            "Remote rejected {refname}: {message}"
+        ))),
+        None => Ok(()),
+    });
+    let mut push_options = PushOptions::new();
+    push_options.remote_callbacks(callbacks);
+
+    remote
+        .push(
+            &[format!(
+                "refs/heads/{}:refs/heads/{}",
+                status.branch, status.upstream.branch
+            )],
+            Some(&mut push_options),
+        )
+        .wrap_err_with(|| {
+            format!(
+                "Failed to push branch {} to {} from {:?}",
+                status.branch, status.upstream, path
+            )
+        })
 }
 
 #[cfg(test)]
@@ -200,6 +922,171 @@ mod tests {
         );
     }
 
+    fn commit(repo: &Repository, message: &str) -> Oid {
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn should_compare_with_configured_upstream() {
+        let dir = tempdir().unwrap();
+        let bare_path = dir.path().join("remote.git");
+        Repository::init_bare(&bare_path).unwrap();
+        let local_path = dir.path().join("local");
+        let repo = Repository::init_opts(
+            &local_path,
+            git2::RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        assert_eq!(
+            head_state(&local_path).unwrap(),
+            Head::Unborn("main".to_string())
+        );
+        assert!(repo_status(&local_path, &GitAuth::default()).is_err());
+
+        commit(&repo, "Initial commit");
+        let mut remote = repo
+            .remote("upstream", bare_path.to_str().unwrap())
+            .unwrap();
+        remote
+            .push(&["refs/heads/main:refs/heads/trunk"], None)
+            .unwrap();
+        remote
+            .fetch(
+                &["refs/heads/trunk:refs/remotes/upstream/trunk"],
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(repo_status(&local_path, &GitAuth::default()).is_err());
+        repo.find_branch("main", BranchType::Local)
+            .unwrap()
+            .set_upstream(Some("upstream/trunk"))
+            .unwrap();
+
+        commit(&repo, "Local change");
+        let status = repo_status(&local_path, &GitAuth::default()).unwrap();
+        assert_eq!(status.to_string(), "1 ahead of upstream/trunk");
+
+        push(&local_path, &status, &GitAuth::default()).unwrap();
+        let status = repo_status(&local_path, &GitAuth::default()).unwrap();
+        assert_eq!((status.ahead, status.behind), (0, 0));
+
+        let head = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(head).unwrap();
+        assert_eq!(head_state(&local_path).unwrap(), Head::Detached(head));
+        assert!(repo_status(&local_path, &GitAuth::default()).is_err());
+    }
+
+    #[test]
+    fn should_commit_changed_and_deleted_files() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for file in ["flake.lock", "sub/flake.lock", "other.nix"] {
+            std::fs::write(dir.path().join(file), "old").unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        commit(&repo, "Initial commit");
+
+        std::fs::write(dir.path().join("flake.lock"), "new").unwrap();
+        std::fs::remove_file(dir.path().join("sub/flake.lock")).unwrap();
+        std::fs::write(dir.path().join("other.nix"), "new").unwrap();
+        let locks = changed_files_named(dir.path(), "flake.lock").unwrap();
+        assert_eq!(locks.len(), 2);
+
+        commit_files(dir.path(), &locks, "Update flake.lock", None).unwrap();
+
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_path(Path::new("flake.lock")).is_ok());
+        assert!(tree.get_path(Path::new("sub/flake.lock")).is_err());
+        assert_eq!(
+            changed_files(dir.path()).unwrap(),
+            vec![PathBuf::from("other.nix")]
+        );
+    }
+
+    #[test]
+    fn should_try_each_credential_once_then_explain_failure() {
+        let dir = tempdir().unwrap();
+        let key = dir.path().join("id_ed25519");
+        std::fs::write(&key, "key").unwrap();
+        let auth = GitAuth {
+            ssh_agent: true,
+            ssh_keys: vec![dir.path().join("missing"), key.clone()],
+        };
+        let mut credentials = Credentials::new(&auth, None);
+        let url = "ssh://git@example.com/config.git";
+
+        let username = credentials
+            .next(url, None, CredentialType::USERNAME)
+            .unwrap();
+        assert_eq!(username.credtype(), CredentialType::USERNAME.bits());
+        for _ in 0..2 {
+            let cred = credentials
+                .next(url, Some("git"), CredentialType::SSH_KEY)
+                .unwrap();
+            assert_eq!(cred.credtype(), CredentialType::SSH_KEY.bits());
+        }
+        assert_eq!(
+            credentials.tried,
+            vec![Attempt::SshAgent, Attempt::SshKey(key.clone())]
+        );
+
+        let error = credentials
+            .next(url, Some("git"), CredentialType::SSH_KEY)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.message(),
+            format!(
+                "Authentication failed for {}, tried the SSH agent, key {:?}",
+                url, key
+            )
+        );
+    }
+
+    #[test]
+    fn should_use_credential_helper_from_repo_config() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        repo.config()
+            .unwrap()
+            .set_str(
+                "credential.helper",
+                "!f() { echo username=me; echo password=secret; }; f",
+            )
+            .unwrap();
+        let auth = GitAuth::default();
+        let mut credentials = Credentials::new(&auth, Some(repo.config().unwrap()));
+
+        let cred = credentials
+            .next(
+                "https://example.com/config.git",
+                None,
+                CredentialType::USER_PASS_PLAINTEXT,
+            )
+            .unwrap();
+
+        assert_eq!(cred.credtype(), CredentialType::USER_PASS_PLAINTEXT.bits());
+    }
+
     fn join_git_string_array(a: git2::string_array::StringArray) -> String {
         let mut result = String::new();
         let count = a.len();
@@ -207,7 +1094,7 @@ mod tests {
         for i in 0..count {
             if let Some(item) = a.get(i) {
                 if i > 0 {
-                    result.push_str(",");
+                    result.push(',');
                 }
                 result.push_str(item);
             }
@@ -0,0 +1,515 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local, NaiveDate};
+use eyre::{eyre, Result, WrapErr};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::command::{Cmd, CommandOutput, CommandRunner};
+use crate::config::dirty_files;
+use crate::git::{head_commit, is_git_repo};
+use crate::hash::hash_file;
+use crate::platform::ActivationMode;
+use crate::settings::Settings;
+use crate::sync::{walk, EntryType, Filter};
+
+/// How a deployment ended
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// One deployment as recorded in the history journal
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: DateTime<Local>,
+    pub hostname: String,
+    pub commit: Option<String>,
+    pub flags: Vec<String>,
+    /// Uncommitted files in the config dir that were deployed
+    #[serde(default)]
+    pub dirty_files: Vec<String>,
+    /// SHA-256 of each flake.lock under the config path, keyed by relative path
+    pub locks_before: BTreeMap<String, String>,
+    pub locks_after: BTreeMap<String, String>,
+    pub commands: Vec<String>,
+    pub duration_secs: f64,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Lock files whose hash differs before and after the deployment
+    pub fn changed_locks(&self) -> Vec<&str> {
+        self.locks_before
+            .keys()
+            .chain(self.locks_after.keys())
+            .filter(|k| self.locks_before.get(*k) != self.locks_after.get(*k))
+            .map(|k| k.as_str())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Which history entries to show
+#[derive(Clone, Debug, Default)]
+pub struct HistoryFilter {
+    pub hostname: Option<String>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    pub failed: bool,
+    pub limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let day = entry.time.date_naive();
+        self.hostname.as_ref().is_none_or(|h| &entry.hostname == h)
+            && self.since.is_none_or(|since| day >= since)
+            && self.until.is_none_or(|until| day <= until)
+            && (!self.failed || entry.outcome == Outcome::Failure)
+    }
+}
+
+/// Wraps a runner, keeping the command lines it is asked to run for the journal
+pub struct RecordingRunner<'a> {
+    inner: &'a dyn CommandRunner,
+    commands: Mutex<Vec<String>>,
+}
+
+impl<'a> RecordingRunner<'a> {
+    pub fn new(inner: &'a dyn CommandRunner) -> RecordingRunner<'a> {
+        RecordingRunner {
+            inner,
+            commands: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for RecordingRunner<'_> {
+    fn run(&self, cmd: &Cmd) -> Result<()> {
+        self.commands.lock().unwrap().push(cmd.to_string());
+        self.inner.run(cmd)
+    }
+
+    fn output(&self, cmd: &Cmd) -> Result<CommandOutput> {
+        self.commands.lock().unwrap().push(cmd.to_string());
+        self.inner.output(cmd)
+    }
+}
+
+static APPEND_LOCK: Mutex<()> = Mutex::new(());
+
+fn history_file(settings: &Settings) -> PathBuf {
+    settings.state_dir.join("history.jsonl")
+}
+
+/// Hashes every flake.lock under `root` that `filter` doesn't exclude, keyed by path
+/// relative to `root`. Symlinks, like nix's `result`, are not followed.
+pub fn lock_hashes(root: &Path, filter: &Filter) -> Result<BTreeMap<String, String>> {
+    if !root.is_dir() {
+        return Ok(BTreeMap::new());
+    }
+    walk(root, filter)?
+        .into_iter()
+        .filter(|(relative, entry_type)| {
+            *entry_type == EntryType::File
+                && relative.file_name().is_some_and(|n| n == "flake.lock")
+        })
+        .map(|(relative, _)| {
+            let path = root.join(&relative);
+            let hash = hash_file(&path)
+                .wrap_err_with(|| format!("Failed to hash lock file {:?}", path))?;
+            Ok((relative.to_string_lossy().into_owned(), hash))
+        })
+        .collect()
+}
+
+/// The deployment flags in effect, as they would be passed on the command line
+pub fn flags(settings: &Settings) -> Vec<String> {
+    let mut flags: Vec<String> = [
+        (settings.force_evaluation, "--force-eval"),
+        (settings.update, "--update"),
+        (settings.fallback, "--fallback"),
+        (settings.show_trace, "--show-trace"),
+        (settings.rollback_system, "--rollback-system"),
+        (settings.force, "--force"),
+        (settings.commit_lock, "--commit-lock"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, flag)| flag.to_string())
+    .collect();
+    if settings.mode != ActivationMode::Switch {
+        flags.push(format!("--mode={}", settings.mode));
+    }
+    if let Some(input) = &settings.update_input {
+        flags.push(format!("--update-input={}", input));
+    }
+    if let Some(rev) = &settings.rev {
+        flags.push(format!("--rev={}", rev));
+    }
+    if let Some(url) = &settings.repo_url {
+        flags.push(format!("--repo={}", url));
+    }
+    flags
+}
+
+/// The commit checked out in the config path, if it is a git repo
+pub fn config_commit(settings: &Settings) -> Option<String> {
+    if is_git_repo(&settings.config_path) {
+        head_commit(&settings.config_path).ok()
+    } else {
+        None
+    }
+}
+
+/// The config dir as a deployment found it
+#[derive(Clone, Debug, Default)]
+pub struct ConfigState {
+    pub commit: Option<String>,
+    pub dirty_files: Vec<String>,
+    pub locks: BTreeMap<String, String>,
+}
+
+impl ConfigState {
+    pub fn read(settings: &Settings) -> Result<ConfigState> {
+        Ok(ConfigState {
+            commit: config_commit(settings),
+            dirty_files: dirty_files(settings)
+                .map_err(|e| warn!("Not recording uncommitted changes: {:#}", e))
+                .unwrap_or_default(),
+            locks: lock_hashes(
+                &settings.config_path,
+                &Filter::excluding(&settings.sync_exclusions)?,
+            )?,
+        })
+    }
+}
+
+/// Runs a deployment of `hostname`, passing it a runner that records the commands
+/// it runs, then appends it to the history journal. Dry runs are not recorded.
+pub fn journaled<F>(
+    settings: &Settings,
+    hostname: &str,
+    runner: &dyn CommandRunner,
+    deploy: F,
+) -> Result<()>
+where
+    F: FnOnce(&dyn CommandRunner, DateTime<Local>) -> Result<()>,
+{
+    if settings.dry_run {
+        return deploy(runner, Local::now());
+    }
+    create_state_dir(settings, runner)?;
+    // before deploying, as `--commit-lock` moves HEAD on to the lock update
+    let before = ConfigState::read(settings)?;
+    record(settings, hostname, &before, runner, deploy, |settings| {
+        lock_hashes(
+            &settings.config_path,
+            &Filter::excluding(&settings.sync_exclusions)?,
+        )
+    })
+}
+
+/// Like `journaled`, for deployments that leave the config dir alone, so `before`,
+/// read once, serves every host of a fleet deployment
+pub fn journaled_remote<F>(
+    settings: &Settings,
+    hostname: &str,
+    before: &ConfigState,
+    runner: &dyn CommandRunner,
+    deploy: F,
+) -> Result<()>
+where
+    F: FnOnce(&dyn CommandRunner, DateTime<Local>) -> Result<()>,
+{
+    if settings.dry_run {
+        return deploy(runner, Local::now());
+    }
+    create_state_dir(settings, runner)?;
+    record(settings, hostname, before, runner, deploy, |_| {
+        Ok(before.locks.clone())
+    })
+}
+
+fn record<F, L>(
+    settings: &Settings,
+    hostname: &str,
+    before: &ConfigState,
+    runner: &dyn CommandRunner,
+    deploy: F,
+    locks_after: L,
+) -> Result<()>
+where
+    F: FnOnce(&dyn CommandRunner, DateTime<Local>) -> Result<()>,
+    L: FnOnce(&Settings) -> Result<BTreeMap<String, String>>,
+{
+    let deployment_time = Local::now();
+    let recorder = RecordingRunner::new(runner);
+    let result = deploy(&recorder, deployment_time);
+
+    let entry = HistoryEntry {
+        time: deployment_time,
+        hostname: hostname.to_string(),
+        commit: before.commit.clone(),
+        flags: flags(settings),
+        dirty_files: before.dirty_files.clone(),
+        locks_before: before.locks.clone(),
+        locks_after: locks_after(settings)?,
+        commands: recorder.commands(),
+        duration_secs: (Local::now() - deployment_time).num_milliseconds() as f64 / 1000.0,
+        outcome: if result.is_ok() {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        },
+        error: result.as_ref().err().map(|e| {
+            e.chain()
+                .map(|cause| cause.to_string())
+                .collect::<Vec<_>>()
+                .join(": ")
+        }),
+    };
+    if let Err(error) = append_entry(settings, &entry) {
+        warn!("Failed to record deployment history: {:#}", error);
+    }
+
+    result
+}
+
+/// Creates the state dir if it is missing. The system one under `/var/lib` needs root
+/// to create, so it is created with sudo and handed to the deploying user.
+pub fn create_state_dir(settings: &Settings, runner: &dyn CommandRunner) -> Result<()> {
+    let dir = &settings.state_dir;
+    match fs::create_dir_all(dir) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let user = runner.output(&Cmd::new("id").arg("-un"))?;
+            if !user.success() {
+                return Err(eyre!("Failed to get the current user: {}", user.stderr));
+            }
+            runner
+                .run(&Cmd::new("sudo").args([
+                    "install",
+                    "-d",
+                    "-m",
+                    "0755",
+                    "-o",
+                    user.stdout.trim(),
+                    &dir.to_string_lossy(),
+                ]))
+                .wrap_err_with(|| format!("Failed to create state dir {:?}", dir))
+        }
+        result => result.wrap_err_with(|| format!("Failed to create state dir {:?}", dir)),
+    }
+}
+
+pub fn append_entry(settings: &Settings, entry: &HistoryEntry) -> Result<()> {
+    let path = history_file(settings);
+    debug!("Appending deployment to history {:?}", path);
+    fs::create_dir_all(&settings.state_dir)
+        .wrap_err_with(|| format!("Failed to create state dir {:?}", settings.state_dir))?;
+    // fleet deployments append from several threads at once
+    let _guard = APPEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .wrap_err_with(|| format!("Failed to open {:?}", path))?;
+    // one write per entry so concurrent deployments don't interleave lines
+    let line = format!("{}\n", serde_json::to_string(entry)?);
+    file.write_all(line.as_bytes())
+        .wrap_err_with(|| format!("Failed to write to {:?}", path))
+}
+
+pub fn read_history(settings: &Settings) -> Result<Vec<HistoryEntry>> {
+    let path = history_file(settings);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read {:?}", path))?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| serde_json::from_str(l).wrap_err_with(|| format!("Invalid entry in {:?}", path)))
+        .collect()
+}
+
+/// Parses a `YYYY-MM-DD` date for history filters
+pub fn parse_date(s: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|e| eyre!("Invalid date {:?}: {}", s, e))
+}
+
+/// Renders history entries matching `filter`, most recent last. With `verbose`
+/// the commands each deployment ran are listed too.
+pub fn show_history(settings: &Settings, filter: &HistoryFilter, verbose: bool) -> Result<String> {
+    let entries: Vec<HistoryEntry> = read_history(settings)?
+        .into_iter()
+        .filter(|e| filter.matches(e))
+        .collect();
+    let skip = filter
+        .limit
+        .map_or(0, |limit| entries.len().saturating_sub(limit));
+
+    let mut lines = vec![];
+    for entry in entries.iter().skip(skip) {
+        let commit = entry
+            .commit
+            .as_deref()
+            .map_or("-".to_string(), |c| c.chars().take(12).collect());
+        lines.push(format!(
+            "{}  {}  {:<7}  {:>6.1}s  {:<12}  {}",
+            entry.time.format("%Y-%m-%d %H:%M:%S"),
+            entry.hostname,
+            match entry.outcome {
+                Outcome::Success => "ok",
+                Outcome::Failure => "failed",
+            },
+            entry.duration_secs,
+            commit,
+            entry.flags.join(" ")
+        ));
+        if !entry.dirty_files.is_empty() {
+            lines.push(format!("    uncommitted {}", entry.dirty_files.join(", ")));
+        }
+        for lock in entry.changed_locks() {
+            lines.push(format!("    changed {}", lock));
+        }
+        if let Some(error) = &entry.error {
+            lines.push(format!("    error: {}", error));
+        }
+        if verbose {
+            for command in &entry.commands {
+                lines.push(format!("    $ {}", command));
+            }
+        }
+    }
+
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::command::FakeRunner;
+
+    fn entry(day: u32, hostname: &str, outcome: Outcome) -> HistoryEntry {
+        HistoryEntry {
+            time: Local.with_ymd_and_hms(2026, 10, day, 12, 0, 0).unwrap(),
+            hostname: hostname.to_string(),
+            commit: Some("0123456789abcdef".to_string()),
+            flags: vec!["--update".to_string()],
+            dirty_files: vec![],
+            locks_before: BTreeMap::from([("flake.lock".to_string(), "a".to_string())]),
+            locks_after: BTreeMap::from([("flake.lock".to_string(), "b".to_string())]),
+            commands: vec!["sudo nixos-rebuild switch".to_string()],
+            duration_secs: 12.5,
+            error: match outcome {
+                Outcome::Success => None,
+                Outcome::Failure => Some("boom".to_string()),
+            },
+            outcome,
+        }
+    }
+
+    #[test]
+    fn should_round_trip_and_filter_history() {
+        let dir = tempdir().unwrap();
+        let mut settings = Settings::defaults().unwrap();
+        settings.state_dir = dir.path().join("state");
+        for e in [
+            entry(5, "box", Outcome::Success),
+            entry(6, "box", Outcome::Failure),
+            entry(6, "other", Outcome::Success),
+            entry(7, "box", Outcome::Success),
+        ] {
+            append_entry(&settings, &e).unwrap();
+        }
+
+        assert_eq!(read_history(&settings).unwrap().len(), 4);
+
+        let filter = HistoryFilter {
+            hostname: Some("box".to_string()),
+            since: Some(parse_date("2026-10-06").unwrap()),
+            until: Some(parse_date("2026-10-06").unwrap()),
+            ..Default::default()
+        };
+        let shown = show_history(&settings, &filter, true).unwrap();
+        assert_eq!(
+            shown,
+            [
+                "2026-10-06 12:00:00  box  failed     12.5s  0123456789ab  --update",
+                "    changed flake.lock",
+                "    error: boom",
+                "    $ sudo nixos-rebuild switch",
+            ]
+            .join("\n")
+        );
+
+        let last = HistoryFilter {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert!(show_history(&settings, &last, false)
+            .unwrap()
+            .starts_with("2026-10-07"));
+    }
+
+    #[test]
+    fn should_record_commands_passed_to_runner() {
+        let fake = FakeRunner::new();
+        let runner = RecordingRunner::new(&fake);
+
+        runner.run(&Cmd::new("nix").arg("build")).unwrap();
+        runner.output(&Cmd::new("nix").arg("eval")).unwrap();
+
+        assert_eq!(runner.commands(), vec!["nix build", "nix eval"]);
+        assert_eq!(runner.commands(), fake.calls());
+    }
+
+    #[test]
+    fn should_hash_lock_files_relative_to_root() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("flake.lock"), "{}").unwrap();
+        fs::write(dir.path().join("sub/flake.lock"), "{}").unwrap();
+
+        let hashes = lock_hashes(dir.path(), &Filter::new()).unwrap();
+
+        assert_eq!(
+            hashes.keys().collect::<Vec<_>>(),
+            vec!["flake.lock", "sub/flake.lock"]
+        );
+    }
+
+    #[test]
+    fn should_not_follow_symlinks_or_excluded_dirs_when_hashing_locks() {
+        let dir = tempdir().unwrap();
+        let store = dir.path().join("store");
+        fs::create_dir_all(&store).unwrap();
+        fs::write(store.join("flake.lock"), "{}").unwrap();
+        let config = dir.path().join("config");
+        fs::create_dir_all(config.join(".git")).unwrap();
+        fs::write(config.join("flake.lock"), "{}").unwrap();
+        fs::write(config.join(".git/flake.lock"), "{}").unwrap();
+        std::os::unix::fs::symlink(&store, config.join("result")).unwrap();
+
+        let hashes = lock_hashes(&config, &Filter::excluding(&[".git"]).unwrap()).unwrap();
+
+        assert_eq!(hashes.keys().collect::<Vec<_>>(), vec!["flake.lock"]);
+    }
+}
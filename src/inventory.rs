@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::command::CommandRunner;
+use crate::history::{create_state_dir, ConfigState};
+use crate::platform::PlatformKind;
+use crate::remote::{check_remote_configurations, check_remote_options, deploy_remote_as};
+use crate::settings::Settings;
+
+/// The hosts concierge can deploy to, read from an `inventory.toml` like
+///
+/// ```toml
+/// [hosts.web1]
+/// address = "root@10.0.0.5"
+/// tags = ["web"]
+/// ```
+///
+/// Only NixOS hosts can be deployed remotely, so `platform` must be `nixos` if given.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Inventory {
+    #[serde(default)]
+    pub hosts: BTreeMap<String, Host>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    /// ssh destination, e.g. `root@10.0.0.5`
+    pub address: String,
+    /// Only `nixos` is supported
+    #[serde(default = "default_platform")]
+    pub platform: PlatformKind,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// `nixosConfigurations` attribute, defaults to the host's name
+    pub flake_attr: Option<String>,
+}
+
+fn default_platform() -> PlatformKind {
+    PlatformKind::Nixos
+}
+
+impl Host {
+    /// The `nixosConfigurations` attribute of the host called `name`
+    pub fn attr<'a>(&'a self, name: &'a str) -> &'a str {
+        self.flake_attr.as_deref().unwrap_or(name)
+    }
+}
+
+impl Inventory {
+    pub fn read(path: &Path) -> Result<Inventory> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read inventory {:?}", path))?;
+        Inventory::parse(&contents).wrap_err_with(|| format!("Invalid inventory {:?}", path))
+    }
+
+    pub fn parse(contents: &str) -> Result<Inventory> {
+        let inventory: Inventory = toml::from_str(contents)?;
+        // checked up front so a fleet deploy doesn't fail part way through
+        for (name, host) in &inventory.hosts {
+            if host.platform != PlatformKind::Nixos {
+                return Err(eyre!(
+                    "Host {} is {}, only nixos hosts can be deployed remotely",
+                    name,
+                    host.platform
+                ));
+            }
+        }
+        Ok(inventory)
+    }
+
+    /// Hosts having any of `tags`, or every host if `tags` is empty, in name order
+    pub fn select(&self, tags: &[String]) -> Vec<(&str, &Host)> {
+        self.hosts
+            .iter()
+            .filter(|(_, host)| tags.is_empty() || host.tags.iter().any(|t| tags.contains(t)))
+            .map(|(name, host)| (name.as_str(), host))
+            .collect()
+    }
+}
+
+/// How to work through the hosts of a fleet deploy
+#[derive(Clone, Debug)]
+pub struct FleetOptions {
+    /// Hosts deployed at the same time
+    pub jobs: usize,
+    /// Skip the remaining hosts after the first failure
+    pub fail_fast: bool,
+    /// Deploy the first host alone and only continue if it succeeds
+    pub canary: bool,
+    pub build_on_target: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostOutcome {
+    Deployed,
+    Failed(String),
+    Skipped,
+}
+
+#[derive(Debug)]
+pub struct HostResult {
+    pub name: String,
+    pub address: String,
+    pub outcome: HostOutcome,
+    pub duration: Duration,
+}
+
+/// Deploys `hosts` in parallel, returning the result for each in the order given
+pub fn deploy_fleet(
+    settings: &Settings,
+    hosts: &[(&str, &Host)],
+    options: &FleetOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<HostResult>> {
+    // checked up front so a fleet deploy doesn't fail part way through
+    check_remote_options(settings)?;
+    let attrs: Vec<&str> = hosts.iter().map(|(name, host)| host.attr(name)).collect();
+    check_remote_configurations(settings, &attrs, runner)?;
+
+    // once rather than by every host's deployment at the same time
+    if !settings.dry_run {
+        create_state_dir(settings, runner)?;
+    }
+    let config = ConfigState::read(settings)?;
+    let stop = AtomicBool::new(false);
+    let results: Mutex<BTreeMap<usize, HostResult>> = Mutex::new(BTreeMap::new());
+
+    let deploy_one = |index: usize| {
+        let (name, host) = hosts[index];
+        let start = Instant::now();
+        let outcome = if stop.load(Ordering::SeqCst) {
+            HostOutcome::Skipped
+        } else {
+            match deploy_host(settings, name, host, options, &config, runner) {
+                Ok(()) => HostOutcome::Deployed,
+                Err(error) => {
+                    if options.fail_fast {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    HostOutcome::Failed(format!("{:#}", error))
+                }
+            }
+        };
+        results.lock().unwrap().insert(
+            index,
+            HostResult {
+                name: name.to_string(),
+                address: host.address.clone(),
+                outcome,
+                duration: start.elapsed(),
+            },
+        );
+    };
+
+    let mut queue: VecDeque<usize> = (0..hosts.len()).collect();
+    if options.canary {
+        if let Some(first) = queue.pop_front() {
+            println!("*** Deploying canary {}", hosts[first].0);
+            deploy_one(first);
+            if results.lock().unwrap()[&first].outcome != HostOutcome::Deployed {
+                stop.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    let queue = Mutex::new(queue);
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.max(1) {
+            scope.spawn(|| {
+                while let Some(index) = queue.lock().unwrap().pop_front() {
+                    deploy_one(index);
+                }
+            });
+        }
+    });
+
+    Ok(results.into_inner().unwrap().into_values().collect())
+}
+
+fn deploy_host(
+    settings: &Settings,
+    name: &str,
+    host: &Host,
+    options: &FleetOptions,
+    config: &ConfigState,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    deploy_remote_as(
+        settings,
+        &host.address,
+        name,
+        host.attr(name),
+        options.build_on_target,
+        config,
+        runner,
+    )
+}
+
+/// Renders a table with a line per host
+pub fn render_summary(results: &[HostResult]) -> String {
+    let name_width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let address_width = results.iter().map(|r| r.address.len()).max().unwrap_or(0);
+    results
+        .iter()
+        .map(|r| {
+            let outcome = match &r.outcome {
+                HostOutcome::Deployed => "ok".to_string(),
+                HostOutcome::Failed(error) => format!("failed: {}", error),
+                HostOutcome::Skipped => "skipped".to_string(),
+            };
+            format!(
+                "{:<name_width$}  {:<address_width$}  {:>6.1}s  {}",
+                r.name,
+                r.address,
+                r.duration.as_secs_f64(),
+                outcome,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::command::FakeRunner;
+    use crate::history::read_history;
+
+    const INVENTORY: &str = r#"
+[hosts.db1]
+address = "root@10.0.0.9"
+tags = ["db"]
+
+[hosts.web1]
+address = "root@10.0.0.5"
+tags = ["web"]
+flake_attr = "web"
+
+[hosts.web2]
+address = "root@10.0.0.6"
+tags = ["web"]
+"#;
+
+    fn options(jobs: usize) -> FleetOptions {
+        FleetOptions {
+            jobs,
+            fail_fast: false,
+            canary: false,
+            build_on_target: true,
+        }
+    }
+
+    /// Evaluates a flake with a configuration for every host in `INVENTORY`
+    fn fleet_runner() -> FakeRunner {
+        FakeRunner::new().respond("nix eval", 0, r#"["db1","web","web2"]"#)
+    }
+
+    fn fleet_settings() -> (tempfile::TempDir, Settings) {
+        let dir = tempdir().unwrap();
+        let mut settings = Settings::defaults().unwrap();
+        settings.state_dir = dir.path().join("state");
+        (dir, settings)
+    }
+
+    #[test]
+    fn should_select_hosts_by_tag() {
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+
+        let names = |tags: &[&str]| {
+            let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+            inventory
+                .select(&tags)
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&[]), vec!["db1", "web1", "web2"]);
+        assert_eq!(names(&["web"]), vec!["web1", "web2"]);
+        assert!(Inventory::parse("[hosts.x]\nadress = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn should_refuse_hosts_that_are_not_nixos() {
+        let laptop = "[hosts.laptop]\naddress = \"me@laptop\"\nplatform = \"darwin\"\n";
+        let error = Inventory::parse(&format!("{}{}", INVENTORY, laptop)).unwrap_err();
+
+        assert!(error.to_string().contains("laptop is darwin"), "{}", error);
+        assert!(Inventory::parse(&format!("{}platform = \"nixos\"\n", INVENTORY)).is_ok());
+    }
+
+    #[test]
+    fn should_deploy_hosts_in_parallel_and_report_each() {
+        let (_dir, settings) = fleet_settings();
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+        let runner = fleet_runner().fail("root@10.0.0.6");
+
+        let results =
+            deploy_fleet(&settings, &inventory.select(&[]), &options(3), &runner).unwrap();
+
+        let outcomes: Vec<_> = results.iter().map(|r| (&r.name[..], &r.outcome)).collect();
+        assert_eq!(outcomes[0], ("db1", &HostOutcome::Deployed));
+        assert_eq!(outcomes[1], ("web1", &HostOutcome::Deployed));
+        assert!(matches!(outcomes[2].1, HostOutcome::Failed(_)));
+        assert!(runner
+            .calls()
+            .iter()
+            .any(|c| c.contains("#web --target-host root@10.0.0.5")));
+        assert!(render_summary(&results).contains("web2  root@10.0.0.6"));
+        let mut journaled: Vec<String> = read_history(&settings)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.hostname)
+            .collect();
+        journaled.sort();
+        assert_eq!(journaled, vec!["db1", "web1", "web2"]);
+    }
+
+    #[test]
+    fn should_stop_after_failed_canary() {
+        let (_dir, settings) = fleet_settings();
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+        let runner = fleet_runner().fail("root@10.0.0.5");
+        let options = FleetOptions {
+            canary: true,
+            ..options(2)
+        };
+
+        let hosts = inventory.select(&["web".to_string()]);
+        let results = deploy_fleet(&settings, &hosts, &options, &runner).unwrap();
+
+        assert!(matches!(results[0].outcome, HostOutcome::Failed(_)));
+        assert_eq!(results[1].outcome, HostOutcome::Skipped);
+        // the configuration check, then the canary
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn should_skip_remaining_hosts_on_fail_fast() {
+        let (_dir, settings) = fleet_settings();
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+        let runner = fleet_runner().fail("root@10.0.0.9");
+        let options = FleetOptions {
+            fail_fast: true,
+            ..options(1)
+        };
+
+        let results = deploy_fleet(&settings, &inventory.select(&[]), &options, &runner).unwrap();
+
+        assert!(matches!(results[0].outcome, HostOutcome::Failed(_)));
+        assert!(results[1..]
+            .iter()
+            .all(|r| r.outcome == HostOutcome::Skipped));
+    }
+
+    #[test]
+    fn should_check_every_host_before_deploying_any() {
+        let (_dir, settings) = fleet_settings();
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+        let runner = FakeRunner::new().respond("nix eval", 0, r#"["db1"]"#);
+
+        let error = deploy_fleet(&settings, &inventory.select(&[]), &options(2), &runner)
+            .err()
+            .unwrap();
+
+        assert!(
+            error
+                .to_string()
+                .contains("no nixosConfigurations.web, nixosConfigurations.web2"),
+            "{}",
+            error
+        );
+        assert_eq!(runner.calls().len(), 1);
+
+        let mut settings = settings;
+        settings.update();
+        assert!(deploy_fleet(&settings, &inventory.select(&[]), &options(2), &runner).is_err());
+        assert_eq!(runner.calls().len(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+
+/// The parts of a `flake.lock` file concierge cares about
+#[derive(Debug, Deserialize)]
+pub struct FlakeLock {
+    pub nodes: BTreeMap<String, LockNode>,
+    pub root: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LockNode {
+    #[serde(default)]
+    pub inputs: BTreeMap<String, InputRef>,
+    pub locked: Option<Locked>,
+}
+
+/// Where a node is locked to. Which fields are present depends on the input type.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Locked {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub owner: Option<String>,
+    pub repo: Option<String>,
+    pub host: Option<String>,
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+}
+
+impl Locked {
+    pub fn short_rev(&self) -> String {
+        self.rev
+            .as_deref()
+            .map_or("-".to_string(), |r| r.chars().take(12).collect())
+    }
+
+    fn date(&self) -> String {
+        self.last_modified
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .map_or("-".to_string(), |d| d.format("%Y-%m-%d").to_string())
+    }
+
+    /// Web URL of the commits between `self` and `new`, for GitHub and GitLab inputs
+    pub fn compare_url(&self, new: &Locked) -> Option<String> {
+        if self.kind != new.kind || self.owner != new.owner || self.repo != new.repo {
+            return None;
+        }
+        let (owner, repo) = (self.owner.as_ref()?, self.repo.as_ref()?);
+        let (old_rev, new_rev) = (self.rev.as_ref()?, new.rev.as_ref()?);
+        match self.kind.as_str() {
+            "github" => Some(format!(
+                "https://{}/{}/{}/compare/{}...{}",
+                self.host.as_deref().unwrap_or("github.com"),
+                owner,
+                repo,
+                old_rev,
+                new_rev
+            )),
+            "gitlab" => Some(format!(
+                "https://{}/{}/{}/-/compare/{}...{}",
+                self.host.as_deref().unwrap_or("gitlab.com"),
+                owner,
+                repo,
+                old_rev,
+                new_rev
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A root input whose locked revision differs between two lock files
+#[derive(Debug, PartialEq, Eq)]
+pub struct InputChange {
+    pub name: String,
+    pub old: Option<Locked>,
+    pub new: Option<Locked>,
+}
+
+/// An input either names a node directly, or `follows` a path of inputs from the root
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum InputRef {
+    Node(String),
+    Follows(Vec<String>),
+}
+
+impl FlakeLock {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<FlakeLock> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read lock file {:?}", path))?;
+        FlakeLock::parse(&contents)
+            .wrap_err_with(|| format!("Failed to parse lock file {:?}", path))
+    }
+
+    pub fn parse(contents: &str) -> Result<FlakeLock> {
+        Ok(serde_json::from_str(contents)?)
+    }
+
+    /// Names of the flake's own inputs, i.e. those `nix flake update` updates.
+    /// Inputs that `follows` another input are skipped as they are not locked separately.
+    pub fn root_inputs(&self) -> Result<Vec<String>> {
+        let root = self
+            .nodes
+            .get(&self.root)
+            .ok_or_else(|| eyre!("Lock file has no root node {:?}", self.root))?;
+        Ok(root
+            .inputs
+            .iter()
+            .filter(|(_, input)| matches!(input, InputRef::Node(_)))
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    /// Where the root input `name` is locked to
+    fn locked_input(&self, name: &str) -> Option<&Locked> {
+        match self.nodes.get(&self.root)?.inputs.get(name)? {
+            InputRef::Node(node) => self.nodes.get(node)?.locked.as_ref(),
+            InputRef::Follows(_) => None,
+        }
+    }
+
+    /// When each root input's locked revision was last modified, where the lock records it
+    pub fn last_modified(&self) -> Result<Vec<(String, Option<DateTime<Utc>>)>> {
+        Ok(self
+            .root_inputs()?
+            .into_iter()
+            .map(|name| {
+                let modified = self
+                    .locked_input(&name)
+                    .and_then(|l| l.last_modified)
+                    .and_then(|t| DateTime::from_timestamp(t, 0));
+                (name, modified)
+            })
+            .collect())
+    }
+
+    /// Root inputs that were added, removed or re-locked going from `self` to `new`
+    pub fn diff(&self, new: &FlakeLock) -> Result<Vec<InputChange>> {
+        let mut names = self.root_inputs()?;
+        names.extend(new.root_inputs()?);
+        names.sort();
+        names.dedup();
+
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                let old = self.locked_input(&name).cloned();
+                let new = new.locked_input(&name).cloned();
+                (old != new).then_some(InputChange { name, old, new })
+            })
+            .collect())
+    }
+}
+
+/// Renders lock changes as a table with a compare URL per input where one is known
+pub fn render_diff(changes: &[InputChange]) -> String {
+    if changes.is_empty() {
+        return "No flake inputs changed".to_string();
+    }
+
+    let mut rows = vec![[
+        "input".to_string(),
+        "old rev".to_string(),
+        "new rev".to_string(),
+        "old date".to_string(),
+        "new date".to_string(),
+        "changes".to_string(),
+    ]];
+    for change in changes {
+        let (old, new) = (change.old.as_ref(), change.new.as_ref());
+        rows.push([
+            change.name.clone(),
+            old.map_or("-".to_string(), Locked::short_rev),
+            new.map_or("-".to_string(), Locked::short_rev),
+            old.map_or("-".to_string(), Locked::date),
+            new.map_or("-".to_string(), Locked::date),
+            old.zip(new)
+                .and_then(|(old, new)| old.compare_url(new))
+                .unwrap_or_default(),
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..5)
+        .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap_or(0))
+        .collect();
+    rows.iter()
+        .map(|row| {
+            let mut line: String = (0..5)
+                .map(|i| format!("{:<width$}  ", row[i], width = widths[i]))
+                .collect();
+            line.push_str(&row[5]);
+            line.trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"{
+  "nodes": {
+    "home-manager": {
+      "inputs": { "nixpkgs": ["nixpkgs"] },
+      "locked": { "owner": "nix-community", "repo": "home-manager", "rev": "aaaa", "lastModified": 1700000000, "type": "github" }
+    },
+    "nixpkgs": {
+      "locked": { "owner": "NixOS", "repo": "nixpkgs", "rev": "bbbb", "lastModified": 1700000000, "type": "github" }
+    },
+    "root": {
+      "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs", "pinned": ["nixpkgs"] }
+    }
+  },
+  "root": "root",
+  "version": 7
+}"#;
+
+    #[test]
+    fn should_list_root_inputs_without_follows() {
+        let lock = FlakeLock::parse(LOCK).unwrap();
+
+        assert_eq!(lock.root_inputs().unwrap(), vec!["home-manager", "nixpkgs"]);
+    }
+
+    #[test]
+    fn should_diff_relocked_inputs() {
+        let old = FlakeLock::parse(LOCK).unwrap();
+        let new = FlakeLock::parse(&LOCK.replace(
+            r#""rev": "bbbb", "lastModified": 1700000000"#,
+            r#""rev": "cccc", "lastModified": 1710000000"#,
+        ))
+        .unwrap();
+
+        let changes = old.diff(&new).unwrap();
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].name, "nixpkgs");
+        assert_eq!(
+            render_diff(&changes),
+            [
+                "input    old rev  new rev  old date    new date    changes",
+                "nixpkgs  bbbb     cccc     2023-11-14  2024-03-09  https://github.com/NixOS/nixpkgs/compare/bbbb...cccc",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn should_build_gitlab_compare_url() {
+        let locked = |rev: &str| Locked {
+            kind: "gitlab".to_string(),
+            owner: Some("group".to_string()),
+            repo: Some("project".to_string()),
+            host: Some("gitlab.example.com".to_string()),
+            rev: Some(rev.to_string()),
+            last_modified: None,
+        };
+
+        assert_eq!(
+            locked("a").compare_url(&locked("b")).unwrap(),
+            "https://gitlab.example.com/group/project/-/compare/a...b"
+        );
+    }
+}
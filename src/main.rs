@@ -1,76 +1,498 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use clap::{ArgGroup, Parser, Subcommand};
 use eyre::{eyre, Context, Result};
 use log::debug;
-use nix::install_nix;
+use nix::{detect_os, install_nix};
 use settings::Settings;
+use url::Url;
 
+use crate::command::{CommandRunner, DryRunRunner, SystemRunner};
+use crate::config::{checkout_rev, deploy_config_repo, with_dirty_tree_policy, DirtyTreePolicy};
 use crate::deploy::deploy_nix_configuration;
+use crate::drift::{detect_drift, pull_back, render_drift};
+use crate::generations::{rollback, show_generations};
+use crate::history::{parse_date, show_history, HistoryFilter};
+use crate::inventory::{deploy_fleet, render_summary, FleetOptions, HostOutcome, Inventory};
+use crate::lock::{render_diff, FlakeLock};
+use crate::platform::{check_configuration, select, ActivationMode, PlatformKind};
+use crate::remote::deploy_remote;
+use crate::status::Status;
+use crate::sync::{sync, Filter, Rule, SyncOptions};
 
+pub mod closure;
+pub mod command;
 mod config;
 pub mod deploy;
+pub mod drift;
 mod error;
 pub mod fs;
+pub mod generations;
 pub mod git;
 pub mod hash;
+pub mod history;
+pub mod inventory;
+pub mod lock;
 mod nix;
+pub mod platform;
+pub mod remote;
 pub mod settings;
+pub mod status;
+pub mod sync;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    deploy: DeployArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync one directory into another, used by deployments to copy
+    /// configuration with sudo
+    #[command(hide = true)]
+    Sync {
+        /// rsync style filter rule, `+ pattern` to include or `- pattern` to exclude
+        #[arg(long)]
+        filter: Vec<String>,
+
+        /// delete files in the destination that are not in the source
+        #[arg(long)]
+        delete: bool,
+
+        /// don't create directories that would end up empty
+        #[arg(long)]
+        prune_empty_dirs: bool,
+
+        source: PathBuf,
+        destination: PathBuf,
+    },
+
+    /// Deploy the configuration, to this machine unless a target is given
+    #[command(group(ArgGroup::new("remote").args(["target", "all", "tag"]).multiple(true)))]
+    Deploy {
+        /// ssh destination of a NixOS host to deploy to, e.g. `root@web1`
+        #[arg(long)]
+        target: Option<String>,
+
+        /// deploy every host in the inventory
+        #[arg(long, conflicts_with_all = ["target", "tag"])]
+        all: bool,
+
+        /// deploy the inventory hosts with this tag, may be repeated
+        #[arg(long, conflicts_with = "target")]
+        tag: Vec<String>,
+
+        /// build on the target instead of building locally and copying the system over
+        #[arg(long, requires = "remote")]
+        build_on_target: bool,
+
+        /// number of inventory hosts to deploy at the same time
+        #[arg(short, long, default_value_t = 1, requires = "remote")]
+        jobs: usize,
+
+        /// skip the remaining inventory hosts after the first failure
+        #[arg(long, requires = "remote")]
+        fail_fast: bool,
+
+        /// deploy the first inventory host alone and stop if it fails
+        #[arg(long, requires = "remote")]
+        canary: bool,
+    },
+
+    /// Show files edited in the install path since the last deployment
+    Drift {
+        /// copy the edits back into the config path
+        #[arg(long)]
+        pull: bool,
+    },
+
+    /// Work with flake.lock files
+    Lock {
+        #[command(subcommand)]
+        command: LockCommand,
+    },
+
+    /// List system generations and the config commit that built each
+    Generations,
+
+    /// Summarise the state of this host: nix, config repo, generation, inputs and deployments
+    Status {
+        /// print JSON for monitoring instead
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Switch to a previous system generation and restore its config
+    Rollback {
+        /// generation to roll back to, defaults to the one before the current generation
+        #[arg(long)]
+        to: Option<u32>,
+    },
+
+    /// List past deployments recorded on this machine
+    History {
+        /// only show deployments of this host
+        #[arg(long)]
+        hostname: Option<String>,
+
+        /// only show deployments on or after this date (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        since: Option<NaiveDate>,
+
+        /// only show deployments on or before this date (YYYY-MM-DD)
+        #[arg(long, value_parser = parse_date)]
+        until: Option<NaiveDate>,
+
+        /// only show failed deployments
+        #[arg(long)]
+        failed: bool,
+
+        /// show at most this many of the most recent deployments
+        #[arg(short = 'l', long)]
+        limit: Option<usize>,
+
+        /// list the commands each deployment ran
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Inspect concierge's own configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LockCommand {
+    /// Show which flake inputs changed between two lock files
+    Diff { old: PathBuf, new: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective settings and where each value came from
+    Show,
+}
+
+/// Flags controlling a deployment, used by `deploy` or when no subcommand is given
+#[derive(clap::Args, Debug)]
+struct DeployArgs {
     /// Force re-evaluation by tagging flake.nix
-    #[arg(short = 'e', long)]
+    #[arg(short = 'e', long, global = true)]
     force_eval: bool,
 
-    /// Update packages to latest versions
-    #[arg(short, long)]
+    /// Update packages to latest versions. With --repo this implies --commit-lock.
+    #[arg(short, long, global = true)]
     update: bool,
 
     /// Use fallback option to build from source
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     fallback: bool,
 
     /// show trace when evaluating
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     show_trace: bool,
 
     /// update specific flake input
-    #[arg(short, long)]
+    #[arg(short = 'i', long, global = true)]
     update_input: Option<String>,
+
+    /// commit, tag or branch of the config repo to deploy instead of its working tree
+    #[arg(long, global = true)]
+    rev: Option<String>,
+
+    /// show what a deployment would do without changing anything
+    #[arg(short = 'n', long, global = true)]
+    dry_run: bool,
+
+    /// roll the system back to its previous generation if activation fails
+    #[arg(long, global = true)]
+    rollback_system: bool,
+
+    /// deploy even if the install path was edited since the last deployment, losing the edits
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// show what would change and ask before switching to the new configuration
+    #[arg(long, global = true)]
+    confirm: bool,
+
+    /// what to do when the config dir has uncommitted changes
+    #[arg(long, value_enum, global = true)]
+    dirty_tree_policy: Option<DirtyTreePolicy>,
+
+    /// commit the flake.lock files the deployment changed and push them upstream
+    #[arg(long, global = true)]
+    commit_lock: bool,
+
+    /// platform to deploy to instead of the one detected from the OS
+    #[arg(long, value_enum, global = true)]
+    platform: Option<PlatformKind>,
+
+    /// what to do with the built configuration
+    #[arg(long, value_enum, global = true)]
+    mode: Option<ActivationMode>,
+
+    /// configuration name, e.g. `web1`, instead of the hostname
+    #[arg(long, global = true)]
+    host: Option<String>,
+
+    /// git repo to clone into and keep config dir in sync with
+    #[arg(short, long, global = true)]
+    repo: Option<Url>,
 }
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
     let args = Args::parse();
-    // Install Nix if not currently installed.
-    debug!("Checking nix installation");
-    install_nix().wrap_err_with(|| "Error installing Nix.")?;
 
-    // now that we know there is a config in the expected loaction, let's deploy ita
+    // syncing is a self-contained helper so doesn't need settings
+    if let Some(Command::Sync {
+        filter,
+        delete,
+        prune_empty_dirs,
+        source,
+        destination,
+    }) = args.command
+    {
+        let options = SyncOptions {
+            filter: Filter {
+                rules: filter.iter().map(Rule::parse).collect::<Result<_>>()?,
+            },
+            delete,
+            prune_empty_dirs,
+        };
+        for change in sync(&source, &destination, &options)
+            .wrap_err_with(|| format!("Failed to sync {:?} to {:?}", source, destination))?
+        {
+            println!("{}", change);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Lock {
+        command: LockCommand::Diff { old, new },
+    }) = &args.command
+    {
+        let changes = FlakeLock::read(old)?.diff(&FlakeLock::read(new)?)?;
+        println!("{}", render_diff(&changes));
+        return Ok(());
+    }
+
     debug!("Initialising settings");
     let mut settings = Settings::new().wrap_err_with(|| "Failed creating settings")?;
     debug!("Settings initialised:\n{:?}", settings);
 
-    if args.force_eval {
+    let args_deploy = args.deploy;
+    if args_deploy.force_eval {
         settings.force_evaluation();
     }
 
-    if args.update {
+    if args_deploy.update {
         settings.update();
     }
 
-    if args.fallback {
+    if args_deploy.fallback {
         settings.fallback();
     }
 
-    if args.show_trace {
+    if args_deploy.show_trace {
         settings.show_trace();
     }
 
-    if let Some(input) = args.update_input {
+    if let Some(input) = args_deploy.update_input {
         settings.update_input(input);
     }
 
+    if let Some(rev) = args_deploy.rev {
+        settings.rev(rev);
+    }
+
+    if args_deploy.dry_run {
+        settings.dry_run();
+    }
+
+    if args_deploy.rollback_system {
+        settings.rollback_system();
+    }
+
+    if args_deploy.confirm {
+        settings.confirm();
+    }
+
+    if args_deploy.force {
+        settings.force();
+    }
+
+    if let Some(policy) = args_deploy.dirty_tree_policy {
+        settings.dirty_tree_policy(policy);
+    }
+
+    if args_deploy.commit_lock {
+        settings.commit_lock();
+    }
+
+    if let Some(kind) = args_deploy.platform {
+        settings.platform(kind);
+    }
+
+    if let Some(host) = args_deploy.host {
+        settings.flake_attr(host);
+    }
+
+    if let Some(mode) = args_deploy.mode {
+        settings.mode(mode);
+    }
+
+    if let Some(url) = args_deploy.repo {
+        settings.repo_url(url);
+    }
+
+    let runner: &dyn CommandRunner = if settings.dry_run {
+        &DryRunRunner
+    } else {
+        &SystemRunner
+    };
+
+    match args.command {
+        Some(Command::Config {
+            command: ConfigCommand::Show,
+        }) => {
+            println!("{}", settings.show());
+            return Ok(());
+        }
+        Some(Command::Status { json }) => {
+            let status = Status::read(&settings, &detect_os()?, &hostname()?, runner)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                println!("{}", status);
+            }
+            return Ok(());
+        }
+        Some(Command::Drift { pull }) => {
+            let drift = detect_drift(&settings)?;
+            if drift.is_empty() {
+                println!("No edits to {}", settings.install_path_string());
+            } else if pull {
+                pull_back(&settings, &drift)?;
+            } else {
+                println!("{}", render_drift(&drift));
+            }
+            return Ok(());
+        }
+        Some(Command::Generations) => {
+            println!("{}", show_generations(&settings)?);
+            return Ok(());
+        }
+        Some(Command::History {
+            hostname,
+            since,
+            until,
+            failed,
+            limit,
+            verbose,
+        }) => {
+            let filter = HistoryFilter {
+                hostname,
+                since,
+                until,
+                failed,
+                limit,
+            };
+            println!("{}", show_history(&settings, &filter, verbose)?);
+            return Ok(());
+        }
+        Some(Command::Rollback { to }) => {
+            let platform = select(&settings, &detect_os()?, &hostname()?, runner)?;
+            return rollback(&settings, platform.as_ref(), runner, to)
+                .wrap_err_with(|| "Failed to roll back");
+        }
+        Some(Command::Sync { .. })
+        | Some(Command::Lock { .. })
+        | Some(Command::Deploy { .. })
+        | None => {}
+    }
+
+    // Install Nix if not currently installed.
+    if !settings.dry_run {
+        debug!("Checking nix installation");
+        install_nix(runner).wrap_err_with(|| "Error installing Nix.")?;
+    }
+
+    // the dirty tree policy covers every kind of deploy, and stashes before the
+    // config repo is pulled so the pull sees a clean tree
+    let config = settings.clone();
+    with_dirty_tree_policy(&config, || deploy_all(settings, &args.command, runner))
+}
+
+/// Deploys to the targets `command` names, or to this host
+fn deploy_all(
+    mut settings: Settings,
+    command: &Option<Command>,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    if let Some(Command::Deploy {
+        target,
+        all,
+        tag,
+        build_on_target,
+        jobs,
+        fail_fast,
+        canary,
+    }) = command
+    {
+        if target.is_some() || *all || !tag.is_empty() {
+            let _worktree = checkout_rev(&mut settings)?;
+            if let Some(target) = target {
+                return deploy_remote(&settings, target, *build_on_target, runner)
+                    .wrap_err_with(|| format!("Failed to deploy to {}", target));
+            }
+            let options = FleetOptions {
+                jobs: *jobs,
+                fail_fast: *fail_fast,
+                canary: *canary,
+                build_on_target: *build_on_target,
+            };
+            return deploy_inventory(&settings, tag, &options, runner);
+        }
+    }
+
+    let host = hostname()?;
+
+    println!("System hostname: {:?}", host);
+
+    // a dry run leaves the config repo alone too, so only plan from what is already there
+    match settings.repo_url.clone() {
+        Some(url) if !settings.dry_run => {
+            debug!("Syncing config repo {} and deploying", url);
+            let config_path = settings.config_path.clone();
+            let auth = settings.git_auth();
+            deploy_config_repo(config_path, url, &auth, |in_sync| {
+                // updated locks go back to the repo the config comes from
+                if in_sync && settings.update && settings.rev.is_none() {
+                    settings.commit_lock();
+                }
+                deploy(settings, host, runner)
+            })
+            .wrap_err_with(|| "Failed to deploy config repo")?;
+        }
+        _ => deploy(settings, host, runner)?,
+    }
+
+    Ok(())
+}
+
+fn deploy(mut settings: Settings, host: String, runner: &dyn CommandRunner) -> Result<()> {
+    let _worktree = checkout_rev(&mut settings)?;
+
     // Check that configuration is present
     debug!("Checking if flake.nix exists in config dir");
     if !settings.flake_file().exists() {
@@ -85,16 +507,58 @@ fn main() -> Result<()> {
         );
     }
 
-    let host = hostname::get()
+    let platform = select(&settings, &detect_os()?, &host, runner)?;
+    check_configuration(&settings, platform.as_ref(), runner)?;
+
+    debug!("Deploying nix configuration");
+    deploy_nix_configuration(settings, host, platform.as_ref(), runner)
+        .wrap_err_with(|| "Failed to deploy and build nix configuration")
+}
+
+fn deploy_inventory(
+    settings: &Settings,
+    tags: &[String],
+    options: &FleetOptions,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let inventory = Inventory::read(&settings.inventory)?;
+    let hosts = inventory.select(tags);
+    if hosts.is_empty() {
+        return Err(eyre!("No inventory hosts match tags {:?}", tags));
+    }
+
+    let results = deploy_fleet(settings, &hosts, options, runner)?;
+    println!("{}", render_summary(&results));
+
+    let failed = results
+        .iter()
+        .filter(|r| r.outcome != HostOutcome::Deployed)
+        .count();
+    if failed > 0 {
+        return Err(eyre!(
+            "{} of {} hosts were not deployed",
+            failed,
+            results.len()
+        ));
+    }
+    Ok(())
+}
+
+fn hostname() -> Result<String> {
+    Ok(hostname::get()
         .wrap_err_with(|| "Failed to get system hostname.")?
         .to_string_lossy()
-        .to_string();
+        .to_string())
+}
 
-    println!("System hostname: {:?}", host);
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
 
-    debug!("Deploying nix configuration");
-    deploy_nix_configuration(settings, host)
-        .wrap_err_with(|| "Failed to deploy and build nix configuration")?;
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn should_have_valid_cli() {
+        Args::command().debug_assert();
+    }
 }
@@ -1,43 +1,75 @@
-use std::process::{Command, Stdio};
-
 use eyre::{eyre, Result, WrapErr};
 use os_version::OsVersion;
 
-pub fn is_nix_installed() -> bool {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg("nix --version")
-        .output()
-        .expect("failed to execute process");
+use crate::command::{Cmd, CommandRunner};
+
+/// Overrides OS detection, e.g. to exercise the macOS code paths on Linux
+const FORCE_OS_VAR: &str = "CONCIERGE_FORCE_OS";
+
+/// Detects the OS, unless `CONCIERGE_FORCE_OS` is set to `macos`, `nixos` or `linux`
+pub fn detect_os() -> Result<OsVersion> {
+    match std::env::var(FORCE_OS_VAR).ok().as_deref() {
+        None | Some("") => os_version::detect().map_err(|e| eyre!("Failed to detect OS: {:?}", e)),
+        Some(forced) => forced_os(forced),
+    }
+}
+
+fn forced_os(name: &str) -> Result<OsVersion> {
+    let linux = |distro: &str| {
+        OsVersion::Linux(os_version::Linux {
+            distro: distro.to_string(),
+            version: None,
+            version_name: None,
+        })
+    };
+    match name {
+        "macos" => Ok(OsVersion::MacOS(os_version::MacOS {
+            version: "forced".to_string(),
+        })),
+        "nixos" => Ok(linux("nixos")),
+        "linux" => Ok(linux("linux")),
+        _ => Err(eyre!(
+            "Invalid {} {:?}, expected macos, nixos or linux",
+            FORCE_OS_VAR,
+            name
+        )),
+    }
+}
+
+pub fn is_nix_installed(runner: &dyn CommandRunner) -> bool {
+    nix_version(runner).is_some()
+}
+
+/// What `nix --version` reports, `None` if nix is not installed
+pub fn nix_version(runner: &dyn CommandRunner) -> Option<String> {
+    runner
+        .output(&Cmd::new("nix").arg("--version"))
+        .ok()
+        .filter(|output| output.success())
+        .map(|output| output.stdout.trim().to_string())
+}
 
-    output.status.success()
+/// Whether nix-darwin's `darwin-rebuild` is on the PATH
+pub fn is_darwin_rebuild_installed(runner: &dyn CommandRunner) -> bool {
+    runner
+        .output(&Cmd::new("which").arg("darwin-rebuild"))
+        .is_ok_and(|output| output.success())
 }
 
-pub fn install_nix() -> Result<()> {
+pub fn install_nix(runner: &dyn CommandRunner) -> Result<()> {
     // Install Nix if it is not already installed.
-    if !is_nix_installed() {
+    if !is_nix_installed(runner) {
         println!("*** Nix is NOT installed.");
-        let current_os = os_version::detect()
-            .map_err(|e| eyre!(format!("{:?}", e)))
-            .wrap_err_with(|| "Failed to detect os version.")?;
+        let current_os = detect_os().wrap_err_with(|| "Failed to detect os version.")?;
         match current_os {
-            // OsVersion::Linux(_) => {
-            //     println!("*** Current OS is Linux, attempting Linux installation.");
-            //     let mut child = Command::new("sh")
-            //         .arg("-c")
-            //         .arg("curl -L https://nixos.org/nix/install | sh -s -- --daemon")
-            //         .stdout(Stdio::inherit())
-            //         .spawn()?;
-            //     child.wait()?;
-            // }
             OsVersion::MacOS(_) | OsVersion::Linux(_) => {
                 // We install Nix here, extras like nix-darwin are handled later
-                let mut child = Command::new("sh")
-                    .arg("-c")
-                    .arg("curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix | sh -s -- install")
-                    .stdout(Stdio::inherit())
-                    .spawn()?;
-                child.wait()?;
+                runner
+                    .run(&Cmd::new("sh").args([
+                        "-c",
+                        "curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix | sh -s -- install",
+                    ]))
+                    .wrap_err_with(|| "Failed running the Nix installer.")?;
             }
             _ => {
                 return Err(eyre!(
@@ -48,3 +80,15 @@ pub fn install_nix() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_force_os() {
+        assert!(matches!(forced_os("macos").unwrap(), OsVersion::MacOS(_)));
+        assert!(matches!(forced_os("nixos").unwrap(), OsVersion::Linux(l) if l.distro == "nixos"));
+        assert!(forced_os("windows").is_err());
+    }
+}
@@ -0,0 +1,919 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use eyre::{eyre, ContextCompat, Result, WrapErr};
+use log::{debug, info};
+use os_version::OsVersion;
+use serde::{Deserialize, Serialize};
+
+use crate::command::{Cmd, CommandRunner};
+use crate::generations::current_generation;
+use crate::nix::is_darwin_rebuild_installed;
+use crate::settings::Settings;
+
+/// The kinds of host concierge can deploy to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlatformKind {
+    Nixos,
+    Darwin,
+    HomeManager,
+    SystemManager,
+}
+
+impl PlatformKind {
+    /// The platform implied by the OS alone, `None` for Linux other than NixOS
+    /// where it depends on what the flake provides
+    pub fn for_os(os: &OsVersion) -> Option<PlatformKind> {
+        match os {
+            OsVersion::Linux(l) if l.distro == "nixos" => Some(PlatformKind::Nixos),
+            OsVersion::MacOS(_) => Some(PlatformKind::Darwin),
+            _ => None,
+        }
+    }
+
+    /// Where the configuration is installed unless configured otherwise
+    pub fn install_path(self) -> PathBuf {
+        match self {
+            PlatformKind::Nixos => PathBuf::from("/etc/nixos"),
+            _ => PathBuf::from(GENERIC_INSTALL_PATH),
+        }
+    }
+
+    /// Where concierge keeps its state unless configured otherwise. System platforms
+    /// share one journal per host, home-manager keeps it per user.
+    pub fn state_dir(self) -> PathBuf {
+        match self {
+            PlatformKind::HomeManager => {
+                PathBuf::from(shellexpand::tilde("~/.local/state/concierge").into_owned())
+            }
+            _ => PathBuf::from(SYSTEM_STATE_DIR),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PlatformKind::Nixos => "nixos",
+            PlatformKind::Darwin => "darwin",
+            PlatformKind::HomeManager => "home-manager",
+            PlatformKind::SystemManager => "system-manager",
+        }
+    }
+}
+
+impl fmt::Display for PlatformKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What to do with a built configuration, after the `nixos-rebuild` verbs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActivationMode {
+    /// activate it now and make it the boot default
+    #[default]
+    Switch,
+    /// make it the boot default without activating it
+    Boot,
+    /// activate it without making it the boot default
+    Test,
+    /// only build it, leaving a `result` link in the state dir
+    Build,
+    /// show what activating it would change
+    DryActivate,
+}
+
+impl ActivationMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ActivationMode::Switch => "switch",
+            ActivationMode::Boot => "boot",
+            ActivationMode::Test => "test",
+            ActivationMode::Build => "build",
+            ActivationMode::DryActivate => "dry-activate",
+        }
+    }
+
+    /// Whether the mode adds a system generation
+    pub fn creates_generation(self) -> bool {
+        matches!(self, ActivationMode::Switch | ActivationMode::Boot)
+    }
+}
+
+impl fmt::Display for ActivationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Install path for hosts that are not NixOS
+pub const GENERIC_INSTALL_PATH: &str = "/etc/nix-config";
+
+pub const SYSTEM_STATE_DIR: &str = "/var/lib/concierge";
+
+/// Everything about building and activating a configuration that differs between
+/// kinds of host, so the deploy flow itself stays the same everywhere
+pub trait Platform: fmt::Debug {
+    fn kind(&self) -> PlatformKind;
+
+    /// The flake output holding the configuration and its attribute there,
+    /// e.g. `("nixosConfigurations", "web1")`
+    fn configuration(&self) -> (&'static str, &str);
+
+    /// Where the configuration is installed unless configured otherwise
+    fn install_path(&self) -> PathBuf {
+        self.kind().install_path()
+    }
+
+    /// Whether updating the installed flake needs sudo
+    fn needs_sudo(&self) -> bool {
+        true
+    }
+
+    /// Gets the host ready to build, e.g. by installing tools the platform needs.
+    /// Returns true if doing so already activated the configuration.
+    fn prepare(&self, _settings: &Settings, _runner: &dyn CommandRunner) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Builds the configuration without activating it, linking the result at
+    /// `settings.build_result_path()`
+    fn build_command(&self, settings: &Settings) -> Result<Cmd>;
+
+    /// The platform tool's verb for `mode`, `None` if the platform can't do it
+    fn mode_verb(&self, mode: ActivationMode) -> Option<&'static str> {
+        match mode {
+            ActivationMode::Switch | ActivationMode::Build => Some(mode.name()),
+            _ => None,
+        }
+    }
+
+    /// Activates the configuration as `settings.mode` says
+    fn activate_command(&self, settings: &Settings) -> Result<Cmd>;
+
+    /// Switches back to the previous system generation, if the platform has them
+    fn rollback_command(&self) -> Option<Cmd> {
+        None
+    }
+
+    /// Switches to system generation `generation`
+    fn switch_generation_commands(
+        &self,
+        _settings: &Settings,
+        _generation: u32,
+    ) -> Result<Vec<Cmd>> {
+        Err(eyre!(
+            "Switching generations is not supported on {}",
+            self.kind()
+        ))
+    }
+
+    /// Number of the system generation in use, if the platform has them
+    fn current_generation(&self, _settings: &Settings) -> Option<u32> {
+        None
+    }
+}
+
+/// Picks the platform to deploy to: `settings.platform` if set, otherwise from
+/// the OS and, on Linux other than NixOS, the outputs the flake provides.
+/// The configuration is `settings.flake_attr`, or the one named after `hostname`.
+pub fn select(
+    settings: &Settings,
+    os: &OsVersion,
+    hostname: &str,
+    runner: &dyn CommandRunner,
+) -> Result<Box<dyn Platform>> {
+    let host = settings.flake_attr.as_deref().unwrap_or(hostname);
+    let kind = match (settings.platform, PlatformKind::for_os(os)) {
+        (Some(kind), _) | (None, Some(kind)) => kind,
+        (None, None) if matches!(os, OsVersion::Linux(_)) => {
+            let systems = flake_attr_names(settings, "systemConfigs", runner)?;
+            if systems.iter().any(|h| h == host) {
+                PlatformKind::SystemManager
+            } else {
+                let attr = home_candidates(settings, hostname)
+                    .and_then(|candidates| home_configuration(settings, &candidates, runner))
+                    .wrap_err_with(|| {
+                        format!(
+                            "Flake has no systemConfigs.{}, don't know how to activate it \
+                             on this Linux host, choose a platform with --platform",
+                            host
+                        )
+                    })?;
+                info!(
+                    "Flake has no systemConfigs.{}, activating homeConfigurations.\"{}\" with home-manager",
+                    host, attr
+                );
+                return Ok(Box::new(HomeManager { attr }));
+            }
+        }
+        (None, None) => return Err(eyre!("Unsupported OS")),
+    };
+    debug!("Deploying to platform {}", kind);
+
+    Ok(match kind {
+        PlatformKind::Nixos => Box::new(NixOs {
+            host: host.to_string(),
+        }),
+        PlatformKind::Darwin => Box::new(Darwin {
+            host: host.to_string(),
+        }),
+        PlatformKind::SystemManager => Box::new(SystemManager {
+            host: host.to_string(),
+        }),
+        PlatformKind::HomeManager if settings.flake_attr.is_some() => Box::new(HomeManager {
+            attr: host.to_string(),
+        }),
+        PlatformKind::HomeManager => Box::new(HomeManager {
+            attr: home_configuration(settings, &home_candidates(settings, hostname)?, runner)?,
+        }),
+    })
+}
+
+/// The first of `candidates` the flake has in `homeConfigurations`
+fn home_configuration(
+    settings: &Settings,
+    candidates: &[String],
+    runner: &dyn CommandRunner,
+) -> Result<String> {
+    let home_configs = flake_attr_names(settings, "homeConfigurations", runner)?;
+    candidates
+        .iter()
+        .find(|attr| home_configs.contains(attr))
+        .cloned()
+        .ok_or_else(|| {
+            eyre!(
+                "Flake has no homeConfigurations.{}",
+                candidates
+                    .iter()
+                    .map(|attr| format!("\"{}\"", attr))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            )
+        })
+}
+
+/// The `homeConfigurations` attributes to look for: `settings.flake_attr` if set,
+/// otherwise `user@host` then `user`, as home-manager looks them up
+fn home_candidates(settings: &Settings, hostname: &str) -> Result<Vec<String>> {
+    if let Some(attr) = &settings.flake_attr {
+        return Ok(vec![attr.clone()]);
+    }
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .wrap_err_with(|| "Failed to get current user from $USER")?;
+    Ok(vec![format!("{}@{}", user, hostname), user])
+}
+
+/// The verb for `settings.mode`, or an error if `platform` doesn't support it
+fn verb(platform: &dyn Platform, settings: &Settings) -> Result<&'static str> {
+    platform.mode_verb(settings.mode).ok_or_else(|| {
+        eyre!(
+            "Activation mode {} is not supported on {}",
+            settings.mode,
+            platform.kind()
+        )
+    })
+}
+
+/// Checks `platform` can activate as `settings.mode` says
+pub fn check_mode(settings: &Settings, platform: &dyn Platform) -> Result<()> {
+    verb(platform, settings).map(|_| ())
+}
+
+/// Checks the flake in the config path has the configuration `platform` deploys,
+/// listing the ones it does have if not
+pub fn check_configuration(
+    settings: &Settings,
+    platform: &dyn Platform,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let (output, attr) = platform.configuration();
+    check_configurations(settings, output, &[attr], runner)
+}
+
+/// Checks the flake in the config path has each of `attrs` in flake output `output`,
+/// evaluating it once however many there are
+pub fn check_configurations(
+    settings: &Settings,
+    output: &str,
+    attrs: &[&str],
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let available = flake_attr_names(settings, output, runner)?;
+    let missing: Vec<String> = attrs
+        .iter()
+        .filter(|attr| !available.iter().any(|a| a == *attr))
+        .map(|attr| format!("{}.{}", output, attr))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let hint = if available.is_empty() {
+        format!("it has no {}", output)
+    } else {
+        format!("available: {}", available.join(", "))
+    };
+    Err(eyre!(
+        "Flake in {} has no {}, {}. Choose one with --host or flake_attr.",
+        settings.config_path_string(),
+        missing.join(", "),
+        hint
+    ))
+}
+
+/// Names of the attributes of flake output `output`, empty if the flake has no such output.
+/// Evaluates the config path as the install path may not be populated yet.
+fn flake_attr_names(
+    settings: &Settings,
+    output: &str,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<String>> {
+    let cmd = Cmd::new("nix").args([
+        "eval",
+        "--json",
+        &format!("{}#{}", settings.config_path_string(), output),
+        "--apply",
+        "builtins.attrNames",
+    ]);
+    let output = runner.output(&cmd)?;
+    if !output.success() {
+        debug!("No flake output {}: {}", cmd, output.stderr.trim());
+        return Ok(vec![]);
+    }
+    serde_json::from_str(&output.stdout)
+        .wrap_err_with(|| format!("Unexpected output from {}: {:?}", cmd, output.stdout))
+}
+
+fn install_flake(settings: &Settings) -> Result<&str> {
+    settings.install_path.to_str().wrap_err_with(|| {
+        format!(
+            "Failed to convert install path to string: {:?}",
+            settings.install_path
+        )
+    })
+}
+
+/// The installed flake's configuration `attr`, e.g. `/etc/nixos#web1`
+fn install_flake_attr(settings: &Settings, attr: &str) -> Result<String> {
+    Ok(format!("{}#{}", install_flake(settings)?, attr))
+}
+
+/// A NixOS system from `nixosConfigurations.<host>`
+#[derive(Debug)]
+pub struct NixOs {
+    pub host: String,
+}
+
+impl Platform for NixOs {
+    fn kind(&self) -> PlatformKind {
+        PlatformKind::Nixos
+    }
+
+    fn configuration(&self) -> (&'static str, &str) {
+        ("nixosConfigurations", &self.host)
+    }
+
+    fn build_command(&self, settings: &Settings) -> Result<Cmd> {
+        // nixos-rebuild links the result into the working dir
+        Ok(Cmd::new("nixos-rebuild")
+            .args([
+                "build",
+                "--flake",
+                &install_flake_attr(settings, &self.host)?,
+            ])
+            .current_dir(&settings.state_dir))
+    }
+
+    fn mode_verb(&self, mode: ActivationMode) -> Option<&'static str> {
+        Some(mode.name())
+    }
+
+    fn activate_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("sudo").args([
+            "nixos-rebuild",
+            verb(self, settings)?,
+            "--flake",
+            &install_flake_attr(settings, &self.host)?,
+        ]))
+    }
+
+    fn rollback_command(&self) -> Option<Cmd> {
+        Some(Cmd::new("sudo").args(["nixos-rebuild", "switch", "--rollback"]))
+    }
+
+    fn switch_generation_commands(&self, settings: &Settings, generation: u32) -> Result<Vec<Cmd>> {
+        let profile = settings.system_profile.to_string_lossy();
+        Ok(vec![
+            Cmd::new("sudo").args([
+                "nix-env",
+                "--profile",
+                profile.as_ref(),
+                "--switch-generation",
+                &generation.to_string(),
+            ]),
+            Cmd::new("sudo").args([
+                format!("{}/bin/switch-to-configuration", profile).as_str(),
+                "switch",
+            ]),
+        ])
+    }
+
+    fn current_generation(&self, settings: &Settings) -> Option<u32> {
+        profile_generation(&settings.system_profile)
+    }
+}
+
+/// A macOS system from `darwinConfigurations.<host>` via nix-darwin
+#[derive(Debug)]
+pub struct Darwin {
+    pub host: String,
+}
+
+/// Files nix-darwin takes over but refuses to overwrite, relative to /etc
+const DARWIN_ETC_FILES: [&str; 3] = ["nix/nix.conf", "bashrc", "zshrc"];
+/// The suffix nix-darwin asks for unmanaged files to be moved aside with
+const DARWIN_BACKUP_SUFFIX: &str = ".before-nix-darwin";
+
+impl Platform for Darwin {
+    fn kind(&self) -> PlatformKind {
+        PlatformKind::Darwin
+    }
+
+    fn configuration(&self) -> (&'static str, &str) {
+        ("darwinConfigurations", &self.host)
+    }
+
+    fn needs_sudo(&self) -> bool {
+        false
+    }
+
+    fn prepare(&self, settings: &Settings, runner: &dyn CommandRunner) -> Result<bool> {
+        if is_darwin_rebuild_installed(runner) {
+            return Ok(false);
+        }
+        if settings.mode != ActivationMode::Switch {
+            return Err(eyre!(
+                "darwin-rebuild not found, deploy with --mode switch to bootstrap nix-darwin"
+            ));
+        }
+        println!("*** darwin-rebuild not found, bootstrapping nix-darwin");
+        let flake = install_flake_attr(settings, &self.host)?;
+        bootstrap_darwin(&flake, runner, &settings.etc_path)?;
+        Ok(true)
+    }
+
+    fn build_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("darwin-rebuild")
+            .args([
+                "build",
+                "--flake",
+                &install_flake_attr(settings, &self.host)?,
+            ])
+            .current_dir(&settings.state_dir))
+    }
+
+    fn mode_verb(&self, mode: ActivationMode) -> Option<&'static str> {
+        match mode {
+            ActivationMode::Switch | ActivationMode::Build => Some(mode.name()),
+            // runs the activation checks without activating
+            ActivationMode::DryActivate => Some("check"),
+            ActivationMode::Boot | ActivationMode::Test => None,
+        }
+    }
+
+    fn activate_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("darwin-rebuild").args([
+            verb(self, settings)?,
+            "--flake",
+            &install_flake_attr(settings, &self.host)?,
+        ]))
+    }
+
+    fn rollback_command(&self) -> Option<Cmd> {
+        Some(Cmd::new("darwin-rebuild").args(["switch", "--rollback"]))
+    }
+
+    fn switch_generation_commands(
+        &self,
+        _settings: &Settings,
+        generation: u32,
+    ) -> Result<Vec<Cmd>> {
+        Ok(vec![Cmd::new("darwin-rebuild").args([
+            "switch",
+            "--switch-generation",
+            &generation.to_string(),
+        ])])
+    }
+
+    fn current_generation(&self, settings: &Settings) -> Option<u32> {
+        profile_generation(&settings.system_profile)
+    }
+}
+
+/// Installs nix-darwin by switching to the configuration `flake` with `nix run nix-darwin`,
+/// first moving aside the files in `etc` it would otherwise refuse to replace.
+/// They are moved back if the bootstrap fails.
+fn bootstrap_darwin(flake: &str, runner: &dyn CommandRunner, etc: &Path) -> Result<()> {
+    let mut moved: Vec<(PathBuf, PathBuf)> = vec![];
+    for file in DARWIN_ETC_FILES.iter().map(|f| etc.join(f)) {
+        // a symlink is already managed by nix-darwin
+        if !file.exists() || file.is_symlink() {
+            continue;
+        }
+        let backup = PathBuf::from(format!(
+            "{}{}",
+            file.to_string_lossy(),
+            DARWIN_BACKUP_SUFFIX
+        ));
+        if backup.exists() {
+            return Err(eyre!(
+                "Cannot move {:?} aside for nix-darwin, {:?} already exists",
+                file,
+                backup
+            ));
+        }
+        runner
+            .run(&move_command(&file, &backup))
+            .wrap_err_with(|| format!("Failed to move {:?} aside for nix-darwin", file))?;
+        moved.push((file, backup));
+    }
+
+    let bootstrap =
+        runner.run(&Cmd::new("nix").args(["run", "nix-darwin", "--", "switch", "--flake", flake]));
+    if let Err(error) = bootstrap {
+        for (file, backup) in moved.iter().rev() {
+            if let Err(restore_error) = runner.run(&move_command(backup, file)) {
+                return Err(error.wrap_err(format!(
+                    "Failed to bootstrap nix-darwin and to restore {:?}: {:#}",
+                    file, restore_error
+                )));
+            }
+        }
+        return Err(error.wrap_err("Failed to bootstrap nix-darwin"));
+    }
+
+    Ok(())
+}
+
+fn move_command(from: &Path, to: &Path) -> Cmd {
+    Cmd::new("sudo").args(["mv", &from.to_string_lossy(), &to.to_string_lossy()])
+}
+
+/// A user environment from `homeConfigurations.<attr>`, for hosts where concierge
+/// doesn't manage the system
+#[derive(Debug)]
+pub struct HomeManager {
+    pub attr: String,
+}
+
+impl Platform for HomeManager {
+    fn kind(&self) -> PlatformKind {
+        PlatformKind::HomeManager
+    }
+
+    fn configuration(&self) -> (&'static str, &str) {
+        ("homeConfigurations", &self.attr)
+    }
+
+    fn build_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("home-manager")
+            .args([
+                "build",
+                "--flake",
+                &install_flake_attr(settings, &self.attr)?,
+            ])
+            .current_dir(&settings.state_dir))
+    }
+
+    fn activate_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("home-manager").args([
+            verb(self, settings)?,
+            "--flake",
+            &install_flake_attr(settings, &self.attr)?,
+        ]))
+    }
+}
+
+/// A generic Linux system from `systemConfigs.<host>` via system-manager
+#[derive(Debug)]
+pub struct SystemManager {
+    pub host: String,
+}
+
+impl Platform for SystemManager {
+    fn kind(&self) -> PlatformKind {
+        PlatformKind::SystemManager
+    }
+
+    fn configuration(&self) -> (&'static str, &str) {
+        ("systemConfigs", &self.host)
+    }
+
+    fn build_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("system-manager")
+            .args([
+                "build",
+                "--flake",
+                &install_flake_attr(settings, &self.host)?,
+            ])
+            .current_dir(&settings.state_dir))
+    }
+
+    fn activate_command(&self, settings: &Settings) -> Result<Cmd> {
+        Ok(Cmd::new("sudo").args([
+            "system-manager",
+            verb(self, settings)?,
+            "--flake",
+            &install_flake_attr(settings, &self.host)?,
+        ]))
+    }
+}
+
+fn profile_generation(profile: &Path) -> Option<u32> {
+    current_generation(profile)
+        .ok()
+        .flatten()
+        .map(|generation| generation.number)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::command::{FakeRunner, SystemRunner};
+    use crate::nix::detect_os;
+
+    fn ubuntu() -> OsVersion {
+        OsVersion::Linux(os_version::Linux {
+            distro: "ubuntu".to_string(),
+            version: None,
+            version_name: None,
+        })
+    }
+
+    fn settings() -> Settings {
+        let mut settings = Settings::defaults().unwrap();
+        settings.install_path = PathBuf::from("/etc/nix-config");
+        settings
+    }
+
+    #[test]
+    fn should_activate_home_manager_config_on_other_linux() {
+        let runner = FakeRunner::new().respond("#systemConfigs", 1, "").respond(
+            "#homeConfigurations",
+            0,
+            r#"["me","me@box"]"#,
+        );
+
+        let candidates = ["me@box".to_string(), "me".to_string()];
+        let attr = home_configuration(&settings(), &candidates, &runner).unwrap();
+        let platform = HomeManager { attr };
+
+        assert_eq!(
+            platform.activate_command(&settings()).unwrap().to_string(),
+            "home-manager switch --flake /etc/nix-config#me@box"
+        );
+    }
+
+    #[test]
+    fn should_prefer_system_manager_config_for_host() {
+        let runner = FakeRunner::new()
+            .respond("#systemConfigs", 0, r#"["box"]"#)
+            .respond("#homeConfigurations", 0, r#"["me"]"#);
+
+        let platform = select(&settings(), &ubuntu(), "box", &runner).unwrap();
+
+        assert_eq!(platform.kind(), PlatformKind::SystemManager);
+        assert_eq!(
+            platform.activate_command(&settings()).unwrap().to_string(),
+            "sudo system-manager switch --flake /etc/nix-config#box"
+        );
+        assert!(home_configuration(
+            &settings(),
+            &["me@other".to_string()],
+            &FakeRunner::new().fail("nix eval")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_fail_clearly_without_system_or_home_config_on_other_linux() {
+        let mut settings = settings();
+        settings.flake_attr("box".to_string());
+        let runner = FakeRunner::new()
+            .respond("#systemConfigs", 0, r#"["other"]"#)
+            .respond("#homeConfigurations", 0, r#"["me"]"#);
+
+        let error = select(&settings, &ubuntu(), "host", &runner).unwrap_err();
+
+        let message = format!("{:#}", error);
+        assert!(message.contains("no systemConfigs.box"), "{}", message);
+        assert!(message.contains("--platform"), "{}", message);
+        assert!(
+            message.contains("no homeConfigurations.\"box\""),
+            "{}",
+            message
+        );
+
+        let runner = FakeRunner::new()
+            .respond("#systemConfigs", 0, r#"["other"]"#)
+            .respond("#homeConfigurations", 0, r#"["box"]"#);
+        let platform = select(&settings, &ubuntu(), "host", &runner).unwrap();
+        assert_eq!(platform.kind(), PlatformKind::HomeManager);
+        assert_eq!(platform.configuration(), ("homeConfigurations", "box"));
+    }
+
+    #[test]
+    fn should_select_platform_from_settings_over_os() {
+        let mut settings = settings();
+        settings.platform(PlatformKind::Darwin);
+        let runner = FakeRunner::new();
+
+        let platform = select(&settings, &ubuntu(), "box", &runner).unwrap();
+
+        assert_eq!(platform.kind(), PlatformKind::Darwin);
+        assert!(!platform.needs_sudo());
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn should_deploy_flake_attr_from_settings_over_hostname() {
+        let mut settings = settings();
+        settings.platform(PlatformKind::Nixos);
+        settings.flake_attr("web".to_string());
+        settings.install_path = PathBuf::from("/etc/nixos");
+        let runner = FakeRunner::new().respond("#nixosConfigurations", 0, r#"["box","web"]"#);
+
+        let platform = select(&settings, &ubuntu(), "box", &runner).unwrap();
+
+        assert_eq!(
+            platform.activate_command(&settings).unwrap().to_string(),
+            "sudo nixos-rebuild switch --flake /etc/nixos#web"
+        );
+        assert!(check_configuration(&settings, platform.as_ref(), &runner).is_ok());
+        assert_eq!(
+            runner.calls(),
+            vec![format!(
+                "nix eval --json {}#nixosConfigurations --apply builtins.attrNames",
+                settings.config_path_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn should_list_available_configurations_when_attr_is_missing() {
+        let runner = FakeRunner::new().respond("#darwinConfigurations", 0, r#"["air","mini"]"#);
+        let platform = Darwin {
+            host: "pro".to_string(),
+        };
+
+        let error = check_configuration(&settings(), &platform, &runner).unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .contains("has no darwinConfigurations.pro, available: air, mini"),
+            "{}",
+            error
+        );
+        let runner = FakeRunner::new().fail("nix eval");
+        let error = check_configuration(&settings(), &platform, &runner).unwrap_err();
+        assert!(error.to_string().contains("it has no darwinConfigurations"));
+    }
+
+    #[test]
+    fn should_refuse_unsupported_os() {
+        let runner = FakeRunner::new();
+
+        assert!(select(&settings(), &OsVersion::Unknown, "box", &runner).is_err());
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn should_switch_generations_per_platform() {
+        let settings = settings();
+        let profile = settings.system_profile.to_string_lossy();
+
+        assert_eq!(
+            NixOs {
+                host: "box".to_string()
+            }
+            .switch_generation_commands(&settings, 2)
+            .unwrap()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>(),
+            vec![
+                format!("sudo nix-env --profile {profile} --switch-generation 2"),
+                format!("sudo {profile}/bin/switch-to-configuration switch"),
+            ]
+        );
+        assert_eq!(
+            Darwin {
+                host: "box".to_string()
+            }
+            .switch_generation_commands(&settings, 2)
+            .unwrap()[0]
+                .to_string(),
+            "darwin-rebuild switch --switch-generation 2"
+        );
+        assert!(HomeManager {
+            attr: "me".to_string()
+        }
+        .switch_generation_commands(&settings, 2)
+        .is_err());
+    }
+
+    #[test]
+    fn should_move_etc_files_aside_and_back_when_bootstrap_fails() {
+        let dir = tempdir().unwrap();
+        let etc = dir.path().join("etc");
+        fs::create_dir_all(etc.join("nix")).unwrap();
+        fs::write(etc.join("nix/nix.conf"), "").unwrap();
+        fs::write(etc.join("zshrc"), "").unwrap();
+        let runner = FakeRunner::new().fail("nix run nix-darwin");
+
+        assert!(bootstrap_darwin("/etc/nix-config#box", &runner, &etc).is_err());
+
+        let mv = |from: &str, to: &str| {
+            format!(
+                "sudo mv {} {}",
+                etc.join(from).to_string_lossy(),
+                etc.join(to).to_string_lossy()
+            )
+        };
+        assert_eq!(
+            runner.calls(),
+            vec![
+                mv("nix/nix.conf", "nix/nix.conf.before-nix-darwin"),
+                mv("zshrc", "zshrc.before-nix-darwin"),
+                "nix run nix-darwin -- switch --flake /etc/nix-config#box".to_string(),
+                mv("zshrc.before-nix-darwin", "zshrc"),
+                mv("nix/nix.conf.before-nix-darwin", "nix/nix.conf"),
+            ]
+        );
+    }
+
+    fn stub(bin: &Path, name: &str, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = bin.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn should_bootstrap_nix_darwin_with_stub_tools_on_path() {
+        let dir = tempdir().unwrap();
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        let log = dir.path().join("nix.log");
+        stub(
+            &bin,
+            "nix",
+            &format!("echo \"$@\" >> {}", log.to_string_lossy()),
+        );
+        stub(&bin, "sudo", "exec \"$@\"");
+        let mut settings = settings();
+        settings.etc_path = dir.path().join("etc");
+        fs::create_dir_all(settings.etc_path.join("nix")).unwrap();
+        fs::write(settings.etc_path.join("nix/nix.conf"), "").unwrap();
+        fs::write(settings.etc_path.join("bashrc"), "").unwrap();
+
+        // the real runner and detection, finding the stubs rather than any installed tools
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let stubbed =
+            std::env::join_paths(std::iter::once(bin.clone()).chain(std::env::split_paths(&path)))
+                .unwrap();
+        std::env::set_var("PATH", &stubbed);
+        std::env::set_var("CONCIERGE_FORCE_OS", "macos");
+        let os = detect_os();
+        std::env::remove_var("CONCIERGE_FORCE_OS");
+        let platform = select(&settings, &os.unwrap(), "box", &SystemRunner).unwrap();
+        let bootstrapped = platform.prepare(&settings, &SystemRunner);
+        stub(&bin, "darwin-rebuild", "exit 0");
+        let bootstrapped_again = platform.prepare(&settings, &SystemRunner);
+        std::env::set_var("PATH", &path);
+
+        assert_eq!(platform.kind(), PlatformKind::Darwin);
+        assert!(bootstrapped.unwrap());
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "run nix-darwin -- switch --flake /etc/nix-config#box\n"
+        );
+        for file in ["nix/nix.conf", "bashrc"] {
+            let file = settings.etc_path.join(file);
+            assert!(!file.exists());
+            assert!(
+                PathBuf::from(format!("{}.before-nix-darwin", file.to_string_lossy())).exists()
+            );
+        }
+        assert!(!bootstrapped_again.unwrap());
+    }
+}
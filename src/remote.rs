@@ -0,0 +1,335 @@
+use eyre::{eyre, Result, WrapErr};
+use log::debug;
+
+use crate::command::{Cmd, CommandRunner};
+use crate::history::{journaled_remote, ConfigState};
+use crate::platform::{check_configurations, ActivationMode};
+use crate::settings::Settings;
+
+/// The system profile on NixOS targets
+const REMOTE_SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Remote deploys only build and activate, so refuses the options that need more
+pub fn check_remote_options(settings: &Settings) -> Result<()> {
+    let unsupported: Vec<&str> = [
+        (settings.update, "--update"),
+        (settings.confirm, "--confirm"),
+        (settings.commit_lock, "--commit-lock"),
+        (settings.rollback_system, "--rollback-system"),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, flag)| flag)
+    .collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    Err(eyre!(
+        "{} can't be used deploying to remote hosts with --target, --all or --tag",
+        unsupported.join(", ")
+    ))
+}
+
+/// Checks the flake in the config path has `nixosConfigurations` for each of `hostnames`
+pub fn check_remote_configurations(
+    settings: &Settings,
+    hostnames: &[&str],
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    check_configurations(settings, "nixosConfigurations", hostnames, runner)
+}
+
+/// Asks `destination` for its hostname, which names its `nixosConfigurations` entry
+pub fn target_hostname(destination: &str, runner: &dyn CommandRunner) -> Result<String> {
+    let cmd = Cmd::new("ssh").args([destination, "hostname"]);
+    let output = runner
+        .output(&cmd)
+        .wrap_err_with(|| format!("Failed to connect to {}", destination))?;
+    if !output.success() {
+        return Err(eyre!(
+            "Failed to get hostname of {}: {}",
+            destination,
+            output.stderr.trim()
+        ));
+    }
+    let hostname = output.stdout.trim();
+    if hostname.is_empty() {
+        return Err(eyre!("{} reported an empty hostname", destination));
+    }
+    Ok(hostname.to_string())
+}
+
+/// Deploys the config path to the NixOS host at `destination`, an ssh destination
+/// like `user@host`. The system is built locally and copied over, or with
+/// `build_on_target` built by the target itself, then activated there.
+/// The configuration is `settings.flake_attr`, or the one named after the target's hostname.
+pub fn deploy_remote(
+    settings: &Settings,
+    destination: &str,
+    build_on_target: bool,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    check_remote_options(settings)?;
+    let hostname = match &settings.flake_attr {
+        Some(attr) => attr.clone(),
+        None => target_hostname(destination, runner)?,
+    };
+    check_remote_configurations(settings, &[&hostname], runner)?;
+    deploy_remote_as(
+        settings,
+        destination,
+        &hostname,
+        &hostname,
+        build_on_target,
+        &ConfigState::read(settings)?,
+        runner,
+    )
+}
+
+/// Deploys `nixosConfigurations.<hostname>` to the NixOS host at `destination`,
+/// journaled as host `name` with the config dir as `config` found it
+pub fn deploy_remote_as(
+    settings: &Settings,
+    destination: &str,
+    name: &str,
+    hostname: &str,
+    build_on_target: bool,
+    config: &ConfigState,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    println!("Deploying {} to {}", hostname, destination);
+    if settings.dry_run {
+        println!("*** Dry run, {} will not be changed.", destination);
+    }
+
+    journaled_remote(settings, name, config, runner, |runner, _| {
+        if build_on_target {
+            runner
+                .run(&build_on_target_command(settings, destination, hostname))
+                .wrap_err_with(|| format!("Failed to deploy to {}", destination))
+        } else {
+            build_locally_and_switch(settings, destination, hostname, runner)
+        }
+    })
+}
+
+fn toplevel_flake(settings: &Settings, hostname: &str) -> String {
+    format!(
+        "{}#nixosConfigurations.{}.config.system.build.toplevel",
+        settings.config_path_string(),
+        hostname
+    )
+}
+
+fn build_on_target_command(settings: &Settings, destination: &str, hostname: &str) -> Cmd {
+    Cmd::new("nixos-rebuild").args([
+        settings.mode.name(),
+        "--flake",
+        &format!("{}#{}", settings.config_path_string(), hostname),
+        "--target-host",
+        destination,
+        "--build-host",
+        destination,
+        "--use-remote-sudo",
+    ])
+}
+
+fn build_locally_and_switch(
+    settings: &Settings,
+    destination: &str,
+    hostname: &str,
+    runner: &dyn CommandRunner,
+) -> Result<()> {
+    let build = Cmd::new("nix").args([
+        "build",
+        "--no-link",
+        "--print-out-paths",
+        &toplevel_flake(settings, hostname),
+    ]);
+    // building is not read-only so a dry run only prints it
+    let system = if settings.dry_run {
+        runner.run(&build)?;
+        "<built system>".to_string()
+    } else {
+        let output = runner
+            .output(&build)
+            .wrap_err_with(|| format!("Failed to build system for {}", hostname))?;
+        if !output.success() {
+            return Err(eyre!(
+                "Failed to build system for {}: {}",
+                hostname,
+                output.stderr.trim()
+            ));
+        }
+        output
+            .stdout
+            .lines()
+            .last()
+            .map(|line| line.trim().to_string())
+            .ok_or_else(|| eyre!("nix build printed no output path"))?
+    };
+    debug!("Built system {} for {}", system, hostname);
+    if settings.mode == ActivationMode::Build {
+        println!("Built {} for {}", system, hostname);
+        return Ok(());
+    }
+
+    let mut commands =
+        vec![Cmd::new("nix").args(["copy", "--to", &format!("ssh://{}", destination), &system])];
+    if settings.mode.creates_generation() {
+        commands.push(Cmd::new("ssh").args([
+            destination,
+            "sudo",
+            "nix-env",
+            "--profile",
+            REMOTE_SYSTEM_PROFILE,
+            "--set",
+            &system,
+        ]));
+    }
+    commands.push(Cmd::new("ssh").args([
+        destination,
+        "sudo",
+        &format!("{}/bin/switch-to-configuration", system),
+        settings.mode.name(),
+    ]));
+    for cmd in commands {
+        runner
+            .run(&cmd)
+            .wrap_err_with(|| format!("Failed to activate system on {}", destination))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::command::FakeRunner;
+    use crate::history::read_history;
+
+    fn remote_settings() -> (tempfile::TempDir, Settings) {
+        let dir = tempdir().unwrap();
+        let mut settings = Settings::defaults().unwrap();
+        settings.config_path = dir.path().join("config");
+        settings.state_dir = dir.path().join("state");
+        (dir, settings)
+    }
+
+    #[test]
+    fn should_build_locally_copy_and_switch_on_target() {
+        let (_dir, settings) = remote_settings();
+        let runner = FakeRunner::new()
+            .respond("ssh root@10.0.0.5 hostname", 0, "web1\n")
+            .respond("nix eval", 0, r#"["web1"]"#)
+            .respond("nix build", 0, "/nix/store/abc-nixos-system-web1\n");
+
+        deploy_remote(&settings, "root@10.0.0.5", false, &runner).unwrap();
+
+        let config = settings.config_path_string();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                "ssh root@10.0.0.5 hostname".to_string(),
+                format!("nix eval --json {config}#nixosConfigurations --apply builtins.attrNames"),
+                format!("nix build --no-link --print-out-paths {config}#nixosConfigurations.web1.config.system.build.toplevel"),
+                "nix copy --to ssh://root@10.0.0.5 /nix/store/abc-nixos-system-web1".to_string(),
+                "ssh root@10.0.0.5 sudo nix-env --profile /nix/var/nix/profiles/system --set /nix/store/abc-nixos-system-web1".to_string(),
+                "ssh root@10.0.0.5 sudo /nix/store/abc-nixos-system-web1/bin/switch-to-configuration switch".to_string(),
+            ]
+        );
+        assert_eq!(read_history(&settings).unwrap()[0].hostname, "web1");
+    }
+
+    #[test]
+    fn should_build_on_target_with_nixos_rebuild() {
+        let (_dir, settings) = remote_settings();
+        let runner = FakeRunner::new().respond("hostname", 0, "web1\n").respond(
+            "nix eval",
+            0,
+            r#"["web1"]"#,
+        );
+
+        deploy_remote(&settings, "admin@web1", true, &runner).unwrap();
+
+        assert_eq!(
+            runner.calls()[2],
+            format!(
+                "nixos-rebuild switch --flake {}#web1 --target-host admin@web1 --build-host admin@web1 --use-remote-sudo",
+                settings.config_path_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_only_activate_until_reboot_in_boot_mode() {
+        let (_dir, mut settings) = remote_settings();
+        settings.mode(ActivationMode::Boot);
+        settings.flake_attr("web1".to_string());
+        let runner = FakeRunner::new()
+            .respond("nix eval", 0, r#"["web1"]"#)
+            .respond("nix build", 0, "/nix/store/abc-nixos-system-web1\n");
+
+        deploy_remote(&settings, "root@10.0.0.5", false, &runner).unwrap();
+
+        let calls = runner.calls();
+        assert!(calls[1].starts_with("nix build"), "{:?}", calls);
+        assert_eq!(
+            calls.last().unwrap(),
+            "ssh root@10.0.0.5 sudo /nix/store/abc-nixos-system-web1/bin/switch-to-configuration boot"
+        );
+        assert_eq!(calls.len(), 5);
+    }
+
+    #[test]
+    fn should_fail_when_target_is_unreachable() {
+        let (_dir, settings) = remote_settings();
+        let runner = FakeRunner::new().fail("ssh");
+
+        assert!(deploy_remote(&settings, "nowhere", false, &runner).is_err());
+        assert_eq!(runner.calls(), vec!["ssh nowhere hostname"]);
+    }
+
+    #[test]
+    fn should_refuse_options_remote_deploys_do_not_support() {
+        let setters = [
+            (Settings::update as fn(&mut Settings), "--update"),
+            (Settings::confirm, "--confirm"),
+            (Settings::commit_lock, "--commit-lock"),
+            (Settings::rollback_system, "--rollback-system"),
+        ];
+        for (set, flag) in setters {
+            let (_dir, mut settings) = remote_settings();
+            set(&mut settings);
+            let runner = FakeRunner::new();
+
+            let error = deploy_remote(&settings, "root@10.0.0.5", false, &runner).unwrap_err();
+
+            assert!(error.to_string().starts_with(flag), "{}", error);
+            assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+        }
+    }
+
+    #[test]
+    fn should_check_target_configuration_before_building() {
+        let (_dir, settings) = remote_settings();
+        let runner = FakeRunner::new().respond("hostname", 0, "web9\n").respond(
+            "nix eval",
+            0,
+            r#"["web1","web2"]"#,
+        );
+
+        let error = deploy_remote(&settings, "root@10.0.0.5", false, &runner).unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .contains("has no nixosConfigurations.web9, available: web1, web2"),
+            "{}",
+            error
+        );
+        assert!(!runner.calls().iter().any(|c| c.starts_with("nix build")));
+        assert!(read_history(&settings).unwrap().is_empty());
+    }
+}
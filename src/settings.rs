@@ -1,7 +1,66 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-use eyre::{eyre, Result};
-use os_version::OsVersion;
+use clap::ValueEnum;
+use eyre::{eyre, Result, WrapErr};
+use log::debug;
+use serde::Deserialize;
+use url::Url;
+
+use crate::config::DirtyTreePolicy;
+use crate::git::GitAuth;
+use crate::nix::detect_os;
+use crate::platform::{ActivationMode, PlatformKind, GENERIC_INSTALL_PATH, SYSTEM_STATE_DIR};
+
+const SYSTEM_CONFIG_FILE: &str = "/etc/concierge/config.toml";
+const USER_CONFIG_FILE: &str = "~/.config/concierge/config.toml";
+const ENV_PREFIX: &str = "CONCIERGE_";
+
+/// Where the effective value of a setting came from, lowest precedence first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.to_string_lossy()),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Contents of a concierge `config.toml`. Every field is optional so each
+/// layer only overrides what it sets.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingsFile {
+    force_evaluation: Option<bool>,
+    update: Option<bool>,
+    show_trace: Option<bool>,
+    config_path: Option<String>,
+    install_path: Option<String>,
+    sync_exclusions: Option<Vec<String>>,
+    fallback: Option<bool>,
+    repo_url: Option<String>,
+    state_dir: Option<String>,
+    inventory: Option<String>,
+    rollback_system: Option<bool>,
+    confirm: Option<bool>,
+    platform: Option<PlatformKind>,
+    flake_attr: Option<String>,
+    mode: Option<ActivationMode>,
+    ssh_key: Option<String>,
+    commit_lock: Option<bool>,
+    dirty_tree_policy: Option<DirtyTreePolicy>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Settings {
@@ -13,48 +72,367 @@ pub struct Settings {
     pub sync_exclusions: Vec<String>,
     pub fallback: bool,
     pub update_input: Option<String>,
+    /// Revision of the config repo to deploy instead of its working tree
+    pub rev: Option<String>,
+    pub repo_url: Option<Url>,
+    pub dry_run: bool,
+    /// Deploy even if the install path was edited since the last deployment
+    pub force: bool,
+    /// Where concierge keeps its own state, e.g. snapshots of the install path
+    pub state_dir: PathBuf,
+    /// Hosts `deploy --all` and `deploy --tag` deploy to
+    pub inventory: PathBuf,
+    /// Roll the system back to its previous generation if activation fails
+    pub rollback_system: bool,
+    /// Ask before switching to the newly built system
+    pub confirm: bool,
+    /// Platform to deploy to, detected from the OS and flake if not set
+    pub platform: Option<PlatformKind>,
+    /// Configuration to deploy from the flake, named after the hostname if not set
+    pub flake_attr: Option<String>,
+    /// What to do with the built configuration
+    pub mode: ActivationMode,
+    /// What to do when the config dir has uncommitted changes
+    pub dirty_tree_policy: DirtyTreePolicy,
+    /// Commit and push the flake.lock files a deployment changed
+    pub commit_lock: bool,
+    /// Private key for SSH git remotes, tried after the SSH agent and before the default keys
+    pub ssh_key: Option<PathBuf>,
+    /// The nix profile whose generations are the system generations
+    pub system_profile: PathBuf,
+    /// The system currently running, compared against newly built systems
+    pub current_system: PathBuf,
+    /// The system the machine last booted into
+    pub booted_system: PathBuf,
+    /// System config dir, some of whose files nix-darwin moves aside when bootstrapped
+    pub etc_path: PathBuf,
+    sources: BTreeMap<&'static str, Source>,
 }
 
 impl Settings {
+    /// Builds settings from defaults, then the system and user config files,
+    /// then `CONCIERGE_*` environment variables, in that order of precedence.
     pub fn new() -> Result<Settings> {
+        let files = [
+            PathBuf::from(SYSTEM_CONFIG_FILE),
+            PathBuf::from(shellexpand::tilde(USER_CONFIG_FILE).into_owned()),
+        ];
+        Settings::defaults()?.layered(&files, std::env::vars())
+    }
+
+    pub fn defaults() -> Result<Settings> {
         let config_path = PathBuf::from(shellexpand::tilde("~/.config/nix").into_owned());
-        let os = os_version::detect().map_err(|e| eyre!("Failed to detect OS version: {:?}", e))?;
-        println!("Current OS {:?}", os);
-        let install_path = match os {
-            OsVersion::Linux(l) if l.distro == "nixos" => PathBuf::from("/etc/nixos"),
-            _ => PathBuf::from("/etc/nix-config"),
-        };
+        let os = detect_os()?;
+        debug!("Current OS {:?}", os);
+        let install_path = PlatformKind::for_os(&os)
+            .map_or_else(|| PathBuf::from(GENERIC_INSTALL_PATH), |k| k.install_path());
         Ok(Settings {
             force_evaluation: false,
             update: false,
             show_trace: false,
             config_path,
             install_path,
-            sync_exclusions: vec![".gitignore", ".stfolder", ".git", ".concierge-backup"]
+            sync_exclusions: [".gitignore", ".stfolder", ".git", ".concierge-backup"]
                 .iter()
                 .map(|s| s.to_string())
                 .collect(),
             fallback: false,
             update_input: None,
+            rev: None,
+            repo_url: None,
+            dry_run: false,
+            force: false,
+            state_dir: PathBuf::from(SYSTEM_STATE_DIR),
+            inventory: expand_path("~/.config/concierge/inventory.toml"),
+            rollback_system: false,
+            confirm: false,
+            platform: None,
+            flake_attr: None,
+            mode: ActivationMode::default(),
+            dirty_tree_policy: DirtyTreePolicy::default(),
+            commit_lock: false,
+            ssh_key: None,
+            system_profile: PathBuf::from("/nix/var/nix/profiles/system"),
+            current_system: PathBuf::from("/run/current-system"),
+            booted_system: PathBuf::from("/run/booted-system"),
+            etc_path: PathBuf::from("/etc"),
+            sources: BTreeMap::new(),
         })
     }
 
+    /// Applies each config file that exists in order, then any matching environment variables
+    pub fn layered<I>(mut self, files: &[PathBuf], env: I) -> Result<Settings>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for file in files.iter().filter(|f| f.exists()) {
+            self.apply_file(file)
+                .wrap_err_with(|| format!("Failed to load concierge config {:?}", file))?;
+        }
+
+        for (key, value) in env {
+            if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+                self.apply_env(name, &value)
+                    .wrap_err_with(|| format!("Invalid value for {}: {:?}", key, value))?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read file {:?}", path))?;
+        let file: SettingsFile = toml::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse file {:?}", path))?;
+        let source = Source::File(path.to_path_buf());
+
+        if let Some(v) = file.force_evaluation {
+            self.force_evaluation = v;
+            self.set_source("force_evaluation", source.clone());
+        }
+        if let Some(v) = file.update {
+            self.update = v;
+            self.set_source("update", source.clone());
+        }
+        if let Some(v) = file.show_trace {
+            self.show_trace = v;
+            self.set_source("show_trace", source.clone());
+        }
+        if let Some(v) = file.config_path {
+            self.config_path = expand_path(&v);
+            self.set_source("config_path", source.clone());
+        }
+        if let Some(v) = file.install_path {
+            self.install_path = expand_path(&v);
+            self.set_source("install_path", source.clone());
+        }
+        if let Some(v) = file.sync_exclusions {
+            self.sync_exclusions = v;
+            self.set_source("sync_exclusions", source.clone());
+        }
+        if let Some(v) = file.fallback {
+            self.fallback = v;
+            self.set_source("fallback", source.clone());
+        }
+        if let Some(v) = file.repo_url {
+            self.repo_url = Some(parse_url(&v)?);
+            self.set_source("repo_url", source.clone());
+        }
+        if let Some(v) = file.state_dir {
+            self.state_dir = expand_path(&v);
+            self.set_source("state_dir", source.clone());
+        }
+        if let Some(v) = file.inventory {
+            self.inventory = expand_path(&v);
+            self.set_source("inventory", source.clone());
+        }
+        if let Some(v) = file.rollback_system {
+            self.rollback_system = v;
+            self.set_source("rollback_system", source.clone());
+        }
+        if let Some(v) = file.confirm {
+            self.confirm = v;
+            self.set_source("confirm", source.clone());
+        }
+        if let Some(v) = file.flake_attr {
+            self.flake_attr = Some(v);
+            self.set_source("flake_attr", source.clone());
+        }
+        if let Some(v) = file.mode {
+            self.mode = v;
+            self.set_source("mode", source.clone());
+        }
+        if let Some(v) = file.dirty_tree_policy {
+            self.dirty_tree_policy = v;
+            self.set_source("dirty_tree_policy", source.clone());
+        }
+        if let Some(v) = file.commit_lock {
+            self.commit_lock = v;
+            self.set_source("commit_lock", source.clone());
+        }
+        if let Some(v) = file.ssh_key {
+            self.ssh_key = Some(expand_path(&v));
+            self.set_source("ssh_key", source.clone());
+        }
+        if let Some(v) = file.platform {
+            self.set_platform(v, source);
+        }
+
+        Ok(())
+    }
+
+    fn apply_env(&mut self, name: &str, value: &str) -> Result<()> {
+        let field = match name {
+            "FORCE_EVALUATION" => {
+                self.force_evaluation = parse_bool(value)?;
+                "force_evaluation"
+            }
+            "UPDATE" => {
+                self.update = parse_bool(value)?;
+                "update"
+            }
+            "SHOW_TRACE" => {
+                self.show_trace = parse_bool(value)?;
+                "show_trace"
+            }
+            "CONFIG_PATH" => {
+                self.config_path = expand_path(value);
+                "config_path"
+            }
+            "INSTALL_PATH" => {
+                self.install_path = expand_path(value);
+                "install_path"
+            }
+            "SYNC_EXCLUSIONS" => {
+                self.sync_exclusions = value
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                "sync_exclusions"
+            }
+            "FALLBACK" => {
+                self.fallback = parse_bool(value)?;
+                "fallback"
+            }
+            "REPO_URL" => {
+                self.repo_url = Some(parse_url(value)?);
+                "repo_url"
+            }
+            "STATE_DIR" => {
+                self.state_dir = expand_path(value);
+                "state_dir"
+            }
+            "INVENTORY" => {
+                self.inventory = expand_path(value);
+                "inventory"
+            }
+            "ROLLBACK_SYSTEM" => {
+                self.rollback_system = parse_bool(value)?;
+                "rollback_system"
+            }
+            "CONFIRM" => {
+                self.confirm = parse_bool(value)?;
+                "confirm"
+            }
+            "FLAKE_ATTR" => {
+                self.flake_attr = Some(value.to_string());
+                "flake_attr"
+            }
+            "DIRTY_TREE_POLICY" => {
+                self.dirty_tree_policy = DirtyTreePolicy::from_str(value, true)
+                    .map_err(|e| eyre!("Invalid dirty tree policy {:?}: {}", value, e))?;
+                "dirty_tree_policy"
+            }
+            "COMMIT_LOCK" => {
+                self.commit_lock = parse_bool(value)?;
+                "commit_lock"
+            }
+            "SSH_KEY" => {
+                self.ssh_key = Some(expand_path(value));
+                "ssh_key"
+            }
+            "MODE" => {
+                self.mode = ActivationMode::from_str(value, true)
+                    .map_err(|e| eyre!("Invalid activation mode {:?}: {}", value, e))?;
+                "mode"
+            }
+            "PLATFORM" => {
+                let kind = PlatformKind::from_str(value, true)
+                    .map_err(|e| eyre!("Invalid platform {:?}: {}", value, e))?;
+                self.set_platform(kind, Source::Env(format!("{}{}", ENV_PREFIX, name)));
+                return Ok(());
+            }
+            // not a setting, e.g. something else sharing our prefix
+            _ => return Ok(()),
+        };
+        self.set_source(field, Source::Env(format!("{}{}", ENV_PREFIX, name)));
+        Ok(())
+    }
+
+    fn set_source(&mut self, field: &'static str, source: Source) {
+        self.sources.insert(field, source);
+    }
+
+    /// Where the effective value of `field` came from
+    pub fn source(&self, field: &str) -> Source {
+        self.sources.get(field).cloned().unwrap_or(Source::Default)
+    }
+
+    /// Renders the effective settings, one per line, with where each value came from
+    pub fn show(&self) -> String {
+        let fields: Vec<(&str, String)> = vec![
+            ("config_path", format!("{:?}", self.config_path)),
+            ("install_path", format!("{:?}", self.install_path)),
+            ("sync_exclusions", format!("{:?}", self.sync_exclusions)),
+            (
+                "repo_url",
+                self.repo_url
+                    .as_ref()
+                    .map_or("none".to_string(), |u| format!("{:?}", u.as_str())),
+            ),
+            ("state_dir", format!("{:?}", self.state_dir)),
+            ("inventory", format!("{:?}", self.inventory)),
+            ("force_evaluation", self.force_evaluation.to_string()),
+            ("update", self.update.to_string()),
+            ("fallback", self.fallback.to_string()),
+            ("show_trace", self.show_trace.to_string()),
+            ("rollback_system", self.rollback_system.to_string()),
+            ("confirm", self.confirm.to_string()),
+            ("dirty_tree_policy", self.dirty_tree_policy.to_string()),
+            ("commit_lock", self.commit_lock.to_string()),
+            (
+                "platform",
+                self.platform
+                    .map_or("detected".to_string(), |p| p.to_string()),
+            ),
+            ("mode", self.mode.to_string()),
+            (
+                "flake_attr",
+                self.flake_attr
+                    .as_ref()
+                    .map_or("hostname".to_string(), |a| format!("{:?}", a)),
+            ),
+            (
+                "ssh_key",
+                self.ssh_key
+                    .as_ref()
+                    .map_or("default keys".to_string(), |k| format!("{:?}", k)),
+            ),
+        ];
+
+        let width = fields.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        fields
+            .iter()
+            .map(|(k, v)| format!("{:width$} = {}  ({})", k, v, self.source(k)))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
     pub fn force_evaluation(&mut self) {
         self.force_evaluation = true;
+        self.set_source("force_evaluation", Source::Cli);
     }
 
     pub fn update(&mut self) {
         self.update = true;
+        self.set_source("update", Source::Cli);
     }
 
     pub fn show_trace(&mut self) {
         self.show_trace = true;
+        self.set_source("show_trace", Source::Cli);
     }
 
     pub fn flake_file(&self) -> PathBuf {
         self.config_path.join("flake.nix")
     }
 
+    pub fn lock_file(&self) -> PathBuf {
+        self.config_path.join("flake.lock")
+    }
+
     pub fn push_exclusion<S: AsRef<str>>(&mut self, exclusion: S) {
         let exclusion = exclusion.as_ref();
         self.sync_exclusions.push(exclusion.to_string());
@@ -70,9 +448,222 @@ impl Settings {
 
     pub fn fallback(&mut self) {
         self.fallback = true;
+        self.set_source("fallback", Source::Cli);
     }
 
     pub fn update_input(&mut self, name: String) {
         self.update_input = Some(name);
     }
+
+    pub fn rev(&mut self, rev: String) {
+        self.rev = Some(rev);
+    }
+
+    pub fn rollback_system(&mut self) {
+        self.rollback_system = true;
+        self.set_source("rollback_system", Source::Cli);
+    }
+
+    pub fn confirm(&mut self) {
+        self.confirm = true;
+        self.set_source("confirm", Source::Cli);
+    }
+
+    pub fn dirty_tree_policy(&mut self, policy: DirtyTreePolicy) {
+        self.dirty_tree_policy = policy;
+        self.set_source("dirty_tree_policy", Source::Cli);
+    }
+
+    pub fn commit_lock(&mut self) {
+        self.commit_lock = true;
+        self.set_source("commit_lock", Source::Cli);
+    }
+
+    pub fn flake_attr(&mut self, attr: String) {
+        self.flake_attr = Some(attr);
+        self.set_source("flake_attr", Source::Cli);
+    }
+
+    pub fn mode(&mut self, mode: ActivationMode) {
+        self.mode = mode;
+        self.set_source("mode", Source::Cli);
+    }
+
+    pub fn platform(&mut self, kind: PlatformKind) {
+        self.set_platform(kind, Source::Cli);
+    }
+
+    /// Sets the platform, moving the install path and state dir to the platform's
+    /// defaults unless they were configured explicitly
+    fn set_platform(&mut self, kind: PlatformKind, source: Source) {
+        self.platform = Some(kind);
+        self.set_source("platform", source);
+        if self.source("install_path") == Source::Default {
+            self.install_path = kind.install_path();
+        }
+        if self.source("state_dir") == Source::Default {
+            self.state_dir = kind.state_dir();
+        }
+    }
+
+    /// Copy of the install path taken before a deployment, restored if it fails
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.state_dir.join("snapshot")
+    }
+
+    /// Where the system built for a deployment is linked before it is activated
+    pub fn build_result_path(&self) -> PathBuf {
+        self.state_dir.join("result")
+    }
+
+    /// Marks a configuration activated with `--mode boot` that is waiting for a reboot
+    pub fn pending_reboot_path(&self) -> PathBuf {
+        self.state_dir.join("pending-reboot")
+    }
+
+    /// Hashes of the installed files as the last deployment left them
+    pub fn manifest_path(&self) -> PathBuf {
+        self.state_dir.join("install-manifest.json")
+    }
+
+    /// How to authenticate with the config repo's remotes
+    pub fn git_auth(&self) -> GitAuth {
+        let mut ssh_keys: Vec<PathBuf> = self.ssh_key.iter().cloned().collect();
+        ssh_keys.extend(["~/.ssh/id_ed25519", "~/.ssh/id_ecdsa", "~/.ssh/id_rsa"].map(expand_path));
+        GitAuth {
+            ssh_agent: std::env::var_os("SSH_AUTH_SOCK").is_some(),
+            ssh_keys,
+        }
+    }
+
+    pub fn dry_run(&mut self) {
+        self.dry_run = true;
+    }
+
+    pub fn force(&mut self) {
+        self.force = true;
+    }
+
+    pub fn repo_url(&mut self, url: Url) {
+        self.repo_url = Some(url);
+        self.set_source("repo_url", Source::Cli);
+    }
+}
+
+fn expand_path(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(path).into_owned())
+}
+
+fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).wrap_err_with(|| format!("Invalid repo URL {:?}", url))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" | "" => Ok(false),
+        _ => Err(eyre!("Expected a boolean, got {:?}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn should_layer_files_then_env_by_precedence() {
+        let dir = tempdir().unwrap();
+        let system = dir.path().join("system.toml");
+        let user = dir.path().join("user.toml");
+        fs::write(
+            &system,
+            "install_path = \"/etc/system\"\nfallback = true\nshow_trace = true\n",
+        )
+        .unwrap();
+        fs::write(&user, "install_path = \"/etc/user\"\nfallback = false\n").unwrap();
+
+        let settings = Settings::defaults()
+            .unwrap()
+            .layered(
+                &[system.clone(), user.clone()],
+                env(&[("CONCIERGE_FALLBACK", "yes"), ("HOME", "/root")]),
+            )
+            .unwrap();
+
+        assert_eq!(settings.install_path, PathBuf::from("/etc/user"));
+        assert_eq!(settings.source("install_path"), Source::File(user));
+        assert!(settings.show_trace);
+        assert_eq!(settings.source("show_trace"), Source::File(system));
+        assert!(settings.fallback);
+        assert_eq!(
+            settings.source("fallback"),
+            Source::Env("CONCIERGE_FALLBACK".to_string())
+        );
+        assert_eq!(settings.source("config_path"), Source::Default);
+    }
+
+    #[test]
+    fn should_skip_missing_files_and_reject_unknown_fields() {
+        let dir = tempdir().unwrap();
+        let missing = dir.path().join("missing.toml");
+        let bad = dir.path().join("bad.toml");
+        fs::write(&bad, "instal_path = \"/etc/typo\"\n").unwrap();
+
+        assert!(Settings::defaults()
+            .unwrap()
+            .layered(&[missing], env(&[]))
+            .is_ok());
+        assert!(Settings::defaults()
+            .unwrap()
+            .layered(&[bad], env(&[]))
+            .is_err());
+    }
+
+    #[test]
+    fn should_parse_env_exclusions_and_repo_url() {
+        let settings = Settings::defaults()
+            .unwrap()
+            .layered(
+                &[],
+                env(&[
+                    ("CONCIERGE_SYNC_EXCLUSIONS", ".git, result"),
+                    ("CONCIERGE_REPO_URL", "https://example.com/config.git"),
+                ]),
+            )
+            .unwrap();
+
+        assert_eq!(settings.sync_exclusions, vec![".git", "result"]);
+        assert_eq!(
+            settings.repo_url.unwrap().as_str(),
+            "https://example.com/config.git"
+        );
+    }
+
+    #[test]
+    fn should_keep_state_in_home_only_for_home_manager() {
+        let mut settings = Settings::defaults().unwrap();
+        assert_eq!(settings.state_dir, PathBuf::from(SYSTEM_STATE_DIR));
+
+        settings.platform(PlatformKind::HomeManager);
+        assert!(settings.state_dir.ends_with(".local/state/concierge"));
+        settings.platform(PlatformKind::Nixos);
+        assert_eq!(settings.state_dir, PathBuf::from(SYSTEM_STATE_DIR));
+
+        let mut settings = Settings::defaults()
+            .unwrap()
+            .layered(&[], env(&[("CONCIERGE_STATE_DIR", "/srv/concierge")]))
+            .unwrap();
+        settings.platform(PlatformKind::HomeManager);
+        assert_eq!(settings.state_dir, PathBuf::from("/srv/concierge"));
+    }
 }
@@ -0,0 +1,381 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, Utc};
+use eyre::{Result, WrapErr};
+use log::debug;
+use os_version::OsVersion;
+use serde::{Deserialize, Serialize};
+
+use crate::command::CommandRunner;
+use crate::generations::{current_generation, read_records, Generation};
+use crate::git::{
+    head_commit, head_state, is_git_repo, is_working_tree_clean, repo_status, GitAuth,
+};
+use crate::history::{read_history, Outcome};
+use crate::lock::FlakeLock;
+use crate::nix::nix_version;
+use crate::platform::{select, PlatformKind};
+use crate::settings::Settings;
+use crate::sync::{plan_sync, Filter, SyncOptions};
+
+/// A configuration made the boot default with `--mode boot`, not yet booted into
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingReboot {
+    pub time: DateTime<Local>,
+    pub system: PathBuf,
+}
+
+/// Marks the system just built as waiting for a reboot
+pub fn record_pending_reboot(settings: &Settings) -> Result<()> {
+    let result = settings.build_result_path();
+    let marker = PendingReboot {
+        time: Local::now(),
+        system: fs::canonicalize(&result)
+            .wrap_err_with(|| format!("Failed to resolve built system {:?}", result))?,
+    };
+    let path = settings.pending_reboot_path();
+    debug!("Recording pending reboot {:?} in {:?}", marker, path);
+    fs::write(&path, serde_json::to_string(&marker)?)
+        .wrap_err_with(|| format!("Failed to write {:?}", path))
+}
+
+/// Forgets any pending reboot, e.g. after switching to a newer configuration
+pub fn clear_pending_reboot(settings: &Settings) -> Result<()> {
+    let path = settings.pending_reboot_path();
+    if path.exists() {
+        fs::remove_file(&path).wrap_err_with(|| format!("Failed to remove {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// The pending reboot, unless the machine has since booted into its system
+pub fn pending_reboot(settings: &Settings) -> Result<Option<PendingReboot>> {
+    let path = settings.pending_reboot_path();
+    if !path.exists() {
+        return Ok(None);
+    }
+    let marker: PendingReboot = serde_json::from_str(
+        &fs::read_to_string(&path).wrap_err_with(|| format!("Failed to read {:?}", path))?,
+    )
+    .wrap_err_with(|| format!("Invalid pending reboot marker {:?}", path))?;
+    let booted = fs::canonicalize(&settings.booted_system).ok();
+    Ok((booted.as_ref() != Some(&marker.system)).then_some(marker))
+}
+
+/// The config path's git repo
+#[derive(Debug, Serialize)]
+pub struct ConfigRepo {
+    pub commit: Option<String>,
+    /// HEAD's branch, or where HEAD is if not on a branch with commits
+    pub head: String,
+    /// No uncommitted or untracked files
+    pub clean: bool,
+    /// The branch's upstream, `None` if it couldn't be compared with one
+    pub upstream: Option<String>,
+    pub ahead: Option<usize>,
+    pub behind: Option<usize>,
+}
+
+impl ConfigRepo {
+    fn read(path: &Path, auth: &GitAuth) -> Result<Option<ConfigRepo>> {
+        if !is_git_repo(path) {
+            return Ok(None);
+        }
+        let status = repo_status(path, auth)
+            .map_err(|e| debug!("Not comparing with upstream: {:#}", e))
+            .ok();
+        Ok(Some(ConfigRepo {
+            commit: head_commit(path).ok(),
+            head: head_state(path)?.to_string(),
+            clean: is_working_tree_clean(path)?,
+            upstream: status.as_ref().map(|s| s.upstream.to_string()),
+            ahead: status.as_ref().map(|s| s.ahead),
+            behind: status.as_ref().map(|s| s.behind),
+        }))
+    }
+}
+
+/// A flake input and when its locked revision was last modified
+#[derive(Debug, Serialize)]
+pub struct InputAge {
+    pub name: String,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub age_days: Option<i64>,
+}
+
+/// The outcome of the last deployment of this host
+#[derive(Debug, Serialize)]
+pub struct LastDeployment {
+    pub time: DateTime<Local>,
+    pub outcome: Outcome,
+    pub commit: Option<String>,
+    pub error: Option<String>,
+}
+
+/// The state of this machine as far as concierge knows it. Reading it changes
+/// nothing, though comparing with the config repo's origin fetches from it.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub hostname: String,
+    /// What `nix --version` reports, `None` if nix is not installed
+    pub nix: Option<String>,
+    pub platform: Option<PlatformKind>,
+    pub config_path: PathBuf,
+    pub config_repo: Option<ConfigRepo>,
+    /// Paths that differ between the config and install paths, `None` if they couldn't be compared
+    pub install_differences: Option<usize>,
+    pub generation: Option<Generation>,
+    /// Config commit the current generation was deployed from
+    pub commit: Option<String>,
+    pub inputs: Vec<InputAge>,
+    pub last_deployment: Option<LastDeployment>,
+    pub pending_reboot: Option<PendingReboot>,
+}
+
+impl Status {
+    pub fn read(
+        settings: &Settings,
+        os: &OsVersion,
+        hostname: &str,
+        runner: &dyn CommandRunner,
+    ) -> Result<Status> {
+        let generation = current_generation(&settings.system_profile).unwrap_or(None);
+        let commit = generation.as_ref().and_then(|g| {
+            read_records(settings)
+                .ok()?
+                .into_iter()
+                .rev()
+                .find(|r| r.generation == g.number)?
+                .commit
+        });
+        let platform = select(settings, os, hostname, runner)
+            .map_err(|e| debug!("No platform detected: {:#}", e))
+            .ok()
+            .map(|p| p.kind());
+        let install_differences = install_differences(settings)
+            .map_err(|e| debug!("Not comparing install path: {:#}", e))
+            .ok();
+
+        Ok(Status {
+            hostname: hostname.to_string(),
+            nix: nix_version(runner),
+            platform,
+            config_path: settings.config_path.clone(),
+            config_repo: ConfigRepo::read(&settings.config_path, &settings.git_auth())?,
+            install_differences,
+            generation,
+            commit,
+            inputs: input_ages(settings, Utc::now())?,
+            last_deployment: read_history(settings)?
+                .into_iter()
+                .rev()
+                .find(|e| e.hostname == hostname)
+                .map(|e| LastDeployment {
+                    time: e.time,
+                    outcome: e.outcome,
+                    commit: e.commit,
+                    error: e.error,
+                }),
+            pending_reboot: pending_reboot(settings)?,
+        })
+    }
+}
+
+/// Number of paths a deployment would sync from the config path to the install path
+fn install_differences(settings: &Settings) -> Result<usize> {
+    let options = SyncOptions {
+        filter: Filter::excluding(&settings.sync_exclusions)?,
+        delete: true,
+        ..Default::default()
+    };
+    Ok(plan_sync(&settings.config_path, &settings.install_path, &options)?.len())
+}
+
+fn input_ages(settings: &Settings, now: DateTime<Utc>) -> Result<Vec<InputAge>> {
+    if !settings.lock_file().exists() {
+        return Ok(vec![]);
+    }
+    Ok(FlakeLock::read(settings.lock_file())?
+        .last_modified()?
+        .into_iter()
+        .map(|(name, last_modified)| InputAge {
+            name,
+            last_modified,
+            age_days: last_modified.map(|t| (now - t).num_days()),
+        })
+        .collect())
+}
+
+fn short_commit(commit: Option<&str>) -> String {
+    commit.map_or("unknown".to_string(), |c| c.chars().take(12).collect())
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Host: {}", self.hostname)?;
+        writeln!(f, "Nix: {}", self.nix.as_deref().unwrap_or("not installed"))?;
+        writeln!(
+            f,
+            "Platform: {}",
+            self.platform
+                .map_or("unknown".to_string(), |p| p.to_string())
+        )?;
+        match &self.config_repo {
+            Some(repo) => writeln!(
+                f,
+                "Config: {} at {} on {}, {}{}",
+                self.config_path.to_string_lossy(),
+                short_commit(repo.commit.as_deref()),
+                repo.head,
+                if repo.clean { "clean" } else { "dirty" },
+                match (&repo.upstream, repo.ahead, repo.behind) {
+                    (Some(upstream), Some(ahead), Some(behind)) => {
+                        format!(", {} ahead, {} behind {}", ahead, behind, upstream)
+                    }
+                    _ => ", no upstream".to_string(),
+                }
+            )?,
+            None => writeln!(
+                f,
+                "Config: {}, not a git repo",
+                self.config_path.to_string_lossy()
+            )?,
+        }
+        match self.install_differences {
+            Some(0) => writeln!(f, "Install path: in sync with config")?,
+            Some(n) => writeln!(f, "Install path: {} paths differ from config", n)?,
+            None => writeln!(f, "Install path: not compared")?,
+        }
+        match &self.generation {
+            Some(g) => writeln!(
+                f,
+                "Generation: {} ({}) from commit {}",
+                g.number,
+                g.created.format("%Y-%m-%d %H:%M:%S"),
+                short_commit(self.commit.as_deref())
+            )?,
+            None => writeln!(f, "Generation: none")?,
+        }
+        if !self.inputs.is_empty() {
+            writeln!(f, "Inputs:")?;
+            let width = self.inputs.iter().map(|i| i.name.len()).max().unwrap_or(0);
+            for input in &self.inputs {
+                match (input.last_modified, input.age_days) {
+                    (Some(modified), Some(days)) => writeln!(
+                        f,
+                        "    {:<width$}  {}  {} days old",
+                        input.name,
+                        modified.format("%Y-%m-%d"),
+                        days
+                    )?,
+                    _ => writeln!(f, "    {:<width$}  unknown age", input.name)?,
+                }
+            }
+        }
+        match &self.last_deployment {
+            Some(deployment) => writeln!(
+                f,
+                "Last deployment: {} {}",
+                deployment.time.format("%Y-%m-%d %H:%M:%S"),
+                match deployment.outcome {
+                    Outcome::Success => "ok",
+                    Outcome::Failure => "failed",
+                }
+            )?,
+            None => writeln!(f, "Last deployment: none")?,
+        }
+        match &self.pending_reboot {
+            Some(pending) => write!(
+                f,
+                "Pending reboot: into {} since {}",
+                pending.system.to_string_lossy(),
+                pending.time.format("%Y-%m-%d %H:%M:%S")
+            ),
+            None => write!(f, "Pending reboot: no"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::command::FakeRunner;
+
+    #[test]
+    fn should_report_pending_reboot_until_booted_into_system() {
+        let dir = tempdir().unwrap();
+        let mut settings = Settings::defaults().unwrap();
+        settings.state_dir = dir.path().join("state");
+        settings.booted_system = dir.path().join("booted-system");
+        fs::create_dir_all(&settings.state_dir).unwrap();
+        let old_system = dir.path().join("old-system");
+        let new_system = dir.path().join("new-system");
+        fs::create_dir_all(&old_system).unwrap();
+        fs::create_dir_all(&new_system).unwrap();
+        symlink(&new_system, settings.build_result_path()).unwrap();
+        symlink(&old_system, &settings.booted_system).unwrap();
+
+        record_pending_reboot(&settings).unwrap();
+
+        assert_eq!(
+            pending_reboot(&settings).unwrap().unwrap().system,
+            fs::canonicalize(&new_system).unwrap()
+        );
+
+        fs::remove_file(&settings.booted_system).unwrap();
+        symlink(&new_system, &settings.booted_system).unwrap();
+        assert_eq!(pending_reboot(&settings).unwrap(), None);
+
+        clear_pending_reboot(&settings).unwrap();
+        assert!(!settings.pending_reboot_path().exists());
+    }
+
+    #[test]
+    fn should_summarise_host_state() {
+        let dir = tempdir().unwrap();
+        let mut settings = Settings::defaults().unwrap();
+        settings.config_path = dir.path().join("config");
+        settings.install_path = dir.path().join("install");
+        settings.state_dir = dir.path().join("state");
+        settings.system_profile = dir.path().join("profiles/system");
+        settings.platform(PlatformKind::Nixos);
+        fs::create_dir_all(&settings.config_path).unwrap();
+        fs::create_dir_all(&settings.install_path).unwrap();
+        fs::write(settings.config_path.join("flake.nix"), "{}").unwrap();
+        fs::write(
+            settings.lock_file(),
+            r#"{"nodes": {
+                "nixpkgs": {"locked": {"type": "github", "lastModified": 1790000000}},
+                "root": {"inputs": {"nixpkgs": "nixpkgs"}}
+            }, "root": "root", "version": 7}"#,
+        )
+        .unwrap();
+        let runner = FakeRunner::new().respond("nix --version", 0, "nix (Nix) 2.24.1\n");
+
+        let status = Status::read(&settings, &OsVersion::Unknown, "host", &runner).unwrap();
+
+        assert_eq!(status.nix.as_deref(), Some("nix (Nix) 2.24.1"));
+        assert_eq!(status.platform, Some(PlatformKind::Nixos));
+        assert!(status.config_repo.is_none());
+        assert_eq!(status.install_differences, Some(2));
+        assert_eq!(status.inputs[0].name, "nixpkgs");
+        let text = status.to_string();
+        assert!(
+            text.contains("Install path: 2 paths differ from config"),
+            "{}",
+            text
+        );
+        assert!(text.contains("nixpkgs  2026-09-21"), "{}", text);
+
+        let json: serde_json::Value = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["platform"], "nixos");
+        assert_eq!(json["inputs"][0]["last_modified"], "2026-09-21T14:13:20Z");
+        assert!(json["last_deployment"].is_null());
+    }
+}
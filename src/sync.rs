@@ -0,0 +1,627 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result, WrapErr};
+use glob::Pattern;
+use log::{debug, warn};
+
+use crate::hash::hash_file;
+
+/// An rsync style path pattern.
+///
+/// A pattern without a `/` matches a file or directory name at any depth,
+/// a leading `/` anchors the pattern to the root of the transfer and a
+/// trailing `/` only matches directories.
+#[derive(Clone, Debug)]
+pub struct PathPattern {
+    raw: String,
+    pattern: Pattern,
+    anchored: bool,
+    match_path: bool,
+    dir_only: bool,
+}
+
+impl PathPattern {
+    pub fn new<S: AsRef<str>>(pattern: S) -> Result<PathPattern> {
+        let raw = pattern.as_ref();
+        let dir_only = raw.ends_with('/');
+        let anchored = raw.starts_with('/');
+        let trimmed = raw.trim_end_matches('/').trim_start_matches('/');
+        let pattern =
+            Pattern::new(trimmed).wrap_err_with(|| format!("Invalid sync pattern {:?}", raw))?;
+        Ok(PathPattern {
+            raw: raw.to_string(),
+            pattern,
+            anchored,
+            match_path: anchored || trimmed.contains('/'),
+            dir_only,
+        })
+    }
+
+    /// Whether the path, relative to the root of the transfer, matches
+    pub fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if !self.match_path {
+            return relative
+                .file_name()
+                .is_some_and(|n| self.pattern.matches(&n.to_string_lossy()));
+        }
+
+        if self.anchored {
+            return self.pattern.matches_path(relative);
+        }
+
+        // unanchored patterns with a slash can match any trailing run of components
+        let components: Vec<_> = relative.components().collect();
+        (0..components.len()).any(|i| {
+            let tail: PathBuf = components[i..].iter().collect();
+            self.pattern.matches_path(&tail)
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Rule {
+    Include(PathPattern),
+    Exclude(PathPattern),
+}
+
+impl Rule {
+    /// Parses an rsync filter rule, `+ pattern` to include or `- pattern` to exclude
+    pub fn parse<S: AsRef<str>>(rule: S) -> Result<Rule> {
+        let rule = rule.as_ref();
+        match rule.split_once(' ') {
+            Some(("+", pattern)) => Ok(Rule::Include(PathPattern::new(pattern)?)),
+            Some(("-", pattern)) => Ok(Rule::Exclude(PathPattern::new(pattern)?)),
+            _ => Err(eyre!(
+                "Invalid filter rule {:?}, expected '+ pattern' or '- pattern'",
+                rule
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Include(p) => write!(f, "+ {}", p.raw),
+            Rule::Exclude(p) => write!(f, "- {}", p.raw),
+        }
+    }
+}
+
+/// Ordered include/exclude rules. As with rsync the first matching rule
+/// decides, and paths no rule matches are included.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub rules: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// A filter excluding each of the given patterns, e.g. `sync_exclusions`
+    pub fn excluding<S: AsRef<str>>(patterns: &[S]) -> Result<Filter> {
+        patterns
+            .iter()
+            .try_fold(Filter::new(), |filter, p| filter.exclude(p))
+    }
+
+    pub fn include<S: AsRef<str>>(mut self, pattern: S) -> Result<Filter> {
+        self.rules.push(Rule::Include(PathPattern::new(pattern)?));
+        Ok(self)
+    }
+
+    pub fn exclude<S: AsRef<str>>(mut self, pattern: S) -> Result<Filter> {
+        self.rules.push(Rule::Exclude(PathPattern::new(pattern)?));
+        Ok(self)
+    }
+
+    pub fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .find_map(|rule| match rule {
+                Rule::Include(p) if p.matches(relative, is_dir) => Some(false),
+                Rule::Exclude(p) if p.matches(relative, is_dir) => Some(true),
+                _ => None,
+            })
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    pub filter: Filter,
+    /// Delete files in the destination that are not in the source, like `--delete`
+    pub delete: bool,
+    /// Don't create directories that would end up empty, like `--prune-empty-dirs`
+    pub prune_empty_dirs: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryType {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A single difference between the source and destination of a sync
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    pub entry_type: EntryType,
+    pub content: bool,
+    pub permissions: bool,
+}
+
+impl Change {
+    pub fn is_dir(&self) -> bool {
+        self.entry_type == EntryType::Dir
+    }
+}
+
+/// Formats the change the way `rsync --itemize-changes` does, e.g.
+/// `>f+++++++++ flake.nix`, `>fcs....... hosts.nix` or `*deleting   old.nix`
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.path.to_string_lossy();
+        let suffix = if self.is_dir() { "/" } else { "" };
+
+        if self.kind == ChangeKind::Deleted {
+            return write!(f, "*deleting   {}{}", path, suffix);
+        }
+
+        let update = match (self.entry_type, self.content) {
+            (EntryType::File, true) => '>',
+            (EntryType::File, false) => '.',
+            (_, true) => 'c',
+            (_, false) => '.',
+        };
+        let file_type = match self.entry_type {
+            EntryType::File => 'f',
+            EntryType::Dir => 'd',
+            EntryType::Symlink => 'L',
+        };
+        let attributes = if self.kind == ChangeKind::Added {
+            "+++++++++".to_string()
+        } else {
+            let content = if self.content && self.entry_type == EntryType::File {
+                "cs"
+            } else {
+                ".."
+            };
+            let permissions = if self.permissions { 'p' } else { '.' };
+            format!("{}.{}.....", content, permissions)
+        };
+
+        write!(
+            f,
+            "{}{}{} {}{}",
+            update, file_type, attributes, path, suffix
+        )
+    }
+}
+
+/// Computes what syncing `source` into `destination` would change, without
+/// touching either side. Files are compared by size, then content hash.
+pub fn plan_sync<P: AsRef<Path>>(
+    source: P,
+    destination: P,
+    options: &SyncOptions,
+) -> Result<Vec<Change>> {
+    let source = source.as_ref();
+    let destination = destination.as_ref();
+    let mut changes = BTreeSet::new();
+
+    let mut source_entries = walk(source, &options.filter)?;
+    if options.prune_empty_dirs {
+        source_entries = prune_empty_dirs(source_entries);
+    }
+    let destination_entries = if destination.exists() {
+        walk(destination, &options.filter)?
+    } else {
+        vec![]
+    };
+
+    let destination_set: BTreeSet<&PathBuf> = destination_entries.iter().map(|(p, _)| p).collect();
+    let source_set: BTreeSet<&PathBuf> = source_entries.iter().map(|(p, _)| p).collect();
+
+    for (relative, entry_type) in &source_entries {
+        let change = if !destination_set.contains(relative) {
+            Some(Change {
+                path: relative.clone(),
+                kind: ChangeKind::Added,
+                entry_type: *entry_type,
+                content: true,
+                permissions: true,
+            })
+        } else {
+            compare(
+                relative,
+                *entry_type,
+                &source.join(relative),
+                &destination.join(relative),
+            )?
+        };
+
+        if let Some(change) = change {
+            changes.insert(change);
+        }
+    }
+
+    if options.delete {
+        for (relative, entry_type) in &destination_entries {
+            if !source_set.contains(relative) {
+                changes.insert(Change {
+                    path: relative.clone(),
+                    kind: ChangeKind::Deleted,
+                    entry_type: *entry_type,
+                    content: false,
+                    permissions: false,
+                });
+            }
+        }
+    }
+
+    Ok(changes.into_iter().collect())
+}
+
+/// Syncs `source` into `destination`, preserving permissions, and returns the
+/// changes made. Unchanged files are left alone and existing files are
+/// rewritten in place so they keep their owner.
+pub fn sync<P: AsRef<Path>>(
+    source: P,
+    destination: P,
+    options: &SyncOptions,
+) -> Result<Vec<Change>> {
+    let source = source.as_ref();
+    let destination = destination.as_ref();
+
+    let changes = plan_sync(source, destination, options)?;
+
+    fs::create_dir_all(destination)
+        .wrap_err_with(|| format!("Failed to create destination dir {:?}", destination))?;
+
+    // deepest entries first so directories are empty by the time they are removed
+    for change in changes
+        .iter()
+        .rev()
+        .filter(|c| c.kind == ChangeKind::Deleted)
+    {
+        let target = destination.join(&change.path);
+        debug!("Deleting {:?}", target);
+        let result = if change.is_dir() {
+            fs::remove_dir(&target)
+        } else {
+            fs::remove_file(&target)
+        };
+        match result {
+            Ok(()) => {}
+            // an excluded file inside keeps the directory, as with rsync
+            Err(e) if change.is_dir() && e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                warn!("Cannot delete non-empty directory {:?}", target)
+            }
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed to delete {:?}", target));
+            }
+        }
+    }
+
+    for change in changes.iter().filter(|c| c.kind != ChangeKind::Deleted) {
+        apply(source, destination, change).wrap_err_with(|| {
+            format!(
+                "Failed to sync {:?} from {:?} to {:?}",
+                change.path, source, destination
+            )
+        })?;
+    }
+
+    Ok(changes)
+}
+
+fn apply(source: &Path, destination: &Path, change: &Change) -> Result<()> {
+    let from = source.join(&change.path);
+    let to = destination.join(&change.path);
+    let metadata = fs::symlink_metadata(&from)?;
+
+    // a different kind of entry is in the way, e.g. a file where there is now a directory
+    if let Ok(existing) = fs::symlink_metadata(&to) {
+        if entry_type_of(&existing) != change.entry_type {
+            if existing.is_dir() {
+                fs::remove_dir_all(&to)?;
+            } else {
+                fs::remove_file(&to)?;
+            }
+        }
+    }
+
+    match change.entry_type {
+        EntryType::Dir => {
+            if !to.exists() {
+                fs::create_dir(&to)?;
+            }
+            fs::set_permissions(&to, metadata.permissions())?;
+        }
+        EntryType::Symlink => {
+            if fs::symlink_metadata(&to).is_ok() {
+                fs::remove_file(&to)?;
+            }
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        }
+        EntryType::File => {
+            if change.content {
+                let mut reader = File::open(&from)?;
+                let mut writer = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&to)?;
+                io::copy(&mut reader, &mut writer)?;
+            }
+            fs::set_permissions(&to, metadata.permissions())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn entry_type_of(metadata: &fs::Metadata) -> EntryType {
+    if metadata.file_type().is_symlink() {
+        EntryType::Symlink
+    } else if metadata.is_dir() {
+        EntryType::Dir
+    } else {
+        EntryType::File
+    }
+}
+
+/// Lists every entry under `root` that is not excluded, relative to `root`.
+/// Excluded directories are not descended into.
+pub(crate) fn walk(root: &Path, filter: &Filter) -> Result<Vec<(PathBuf, EntryType)>> {
+    let mut entries = vec![];
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(dir) = pending.pop() {
+        let full = root.join(&dir);
+        for entry in
+            fs::read_dir(&full).wrap_err_with(|| format!("Failed to read dir {:?}", full))?
+        {
+            let entry = entry?;
+            let relative = dir.join(entry.file_name());
+            let entry_type = entry_type_of(&fs::symlink_metadata(entry.path())?);
+            let is_dir = entry_type == EntryType::Dir;
+
+            if filter.is_excluded(&relative, is_dir) {
+                continue;
+            }
+
+            if is_dir {
+                pending.push(relative.clone());
+            }
+            entries.push((relative, entry_type));
+        }
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+/// Drops directories that contain no files once filtering has been applied
+fn prune_empty_dirs(entries: Vec<(PathBuf, EntryType)>) -> Vec<(PathBuf, EntryType)> {
+    let needed: BTreeSet<PathBuf> = entries
+        .iter()
+        .filter(|(_, t)| *t != EntryType::Dir)
+        .flat_map(|(p, _)| p.ancestors().skip(1).map(Path::to_path_buf))
+        .collect();
+
+    entries
+        .into_iter()
+        .filter(|(p, t)| *t != EntryType::Dir || needed.contains(p))
+        .collect()
+}
+
+fn compare(
+    relative: &Path,
+    entry_type: EntryType,
+    from: &Path,
+    to: &Path,
+) -> Result<Option<Change>> {
+    let from_meta = fs::symlink_metadata(from)?;
+    let to_meta = fs::symlink_metadata(to)?;
+
+    let content = match entry_type {
+        _ if entry_type != entry_type_of(&to_meta) => true,
+        EntryType::Dir => false,
+        EntryType::Symlink => fs::read_link(from)? != fs::read_link(to)?,
+        EntryType::File => {
+            from_meta.len() != to_meta.len()
+                || hash_file(from).wrap_err_with(|| format!("Failed to hash {:?}", from))?
+                    != hash_file(to).wrap_err_with(|| format!("Failed to hash {:?}", to))?
+        }
+    };
+    let permissions =
+        entry_type != EntryType::Symlink && from_meta.permissions() != to_meta.permissions();
+
+    if !content && !permissions {
+        return Ok(None);
+    }
+
+    Ok(Some(Change {
+        path: relative.to_path_buf(),
+        kind: ChangeKind::Changed,
+        entry_type,
+        content,
+        permissions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn itemized(changes: &[Change]) -> Vec<String> {
+        changes.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn should_match_patterns_like_rsync() {
+        let name = PathPattern::new(".git").unwrap();
+        let glob = PathPattern::new("*.swp").unwrap();
+        let anchored = PathPattern::new("/result").unwrap();
+        let dir_only = PathPattern::new("cache/").unwrap();
+        let nested = PathPattern::new("hosts/*.key").unwrap();
+
+        assert!(name.matches(Path::new("sub/.git"), true));
+        assert!(glob.matches(Path::new("a/b/file.swp"), false));
+        assert!(anchored.matches(Path::new("result"), false));
+        assert!(!anchored.matches(Path::new("sub/result"), false));
+        assert!(dir_only.matches(Path::new("cache"), true));
+        assert!(!dir_only.matches(Path::new("cache"), false));
+        assert!(nested.matches(Path::new("machines/hosts/a.key"), false));
+        assert!(!nested.matches(Path::new("a.key"), false));
+    }
+
+    #[test]
+    fn should_apply_first_matching_filter_rule() {
+        let filter = Filter::new()
+            .include("*.lock")
+            .and_then(|f| f.include("*/"))
+            .and_then(|f| f.exclude("*"))
+            .unwrap();
+
+        assert!(!filter.is_excluded(Path::new("sub/flake.lock"), false));
+        assert!(!filter.is_excluded(Path::new("sub"), true));
+        assert!(filter.is_excluded(Path::new("sub/flake.nix"), false));
+        assert_eq!(
+            Rule::parse("+ *.lock").unwrap().to_string(),
+            "+ *.lock".to_string()
+        );
+        assert!(Rule::parse("*.lock").is_err());
+    }
+
+    #[test]
+    fn should_plan_itemized_changes() {
+        let source = tempdir().unwrap();
+        let destination = tempdir().unwrap();
+        let s = source.path();
+        let d = destination.path();
+
+        fs::write(s.join("same.nix"), "same").unwrap();
+        fs::write(d.join("same.nix"), "same").unwrap();
+        fs::write(s.join("changed.nix"), "new").unwrap();
+        fs::write(d.join("changed.nix"), "old!").unwrap();
+        fs::create_dir(s.join("modules")).unwrap();
+        fs::write(s.join("modules/new.nix"), "new").unwrap();
+        fs::write(d.join("stale.nix"), "stale").unwrap();
+        fs::create_dir(s.join(".git")).unwrap();
+        fs::write(s.join(".git/HEAD"), "ref").unwrap();
+        fs::write(d.join(".gitignore"), "kept").unwrap();
+
+        let options = SyncOptions {
+            filter: Filter::excluding(&[".git", ".gitignore"]).unwrap(),
+            delete: true,
+            ..Default::default()
+        };
+        let changes = plan_sync(s, d, &options).unwrap();
+
+        assert_eq!(
+            itemized(&changes),
+            vec![
+                ">fcs....... changed.nix",
+                "cd+++++++++ modules/",
+                ">f+++++++++ modules/new.nix",
+                "*deleting   stale.nix",
+            ]
+        );
+    }
+
+    #[test]
+    fn should_sync_with_delete_and_permissions() {
+        let source = tempdir().unwrap();
+        let destination = tempdir().unwrap();
+        let s = source.path();
+        let d = destination.path().join("install");
+
+        fs::create_dir(s.join("dir with spaces")).unwrap();
+        fs::write(s.join("dir with spaces/it's.nix"), "quoted").unwrap();
+        fs::write(s.join("script.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(s.join("script.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(d.join("old/nested")).unwrap();
+        fs::write(d.join("old/nested/file"), "old").unwrap();
+
+        let options = SyncOptions {
+            delete: true,
+            ..Default::default()
+        };
+        sync(s, &d, &options).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(d.join("dir with spaces/it's.nix")).unwrap(),
+            "quoted"
+        );
+        assert_eq!(
+            fs::metadata(d.join("script.sh"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o755
+        );
+        assert!(!d.join("old").exists());
+        assert!(plan_sync(s, &d, &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_only_pull_back_lock_files() {
+        let source = tempdir().unwrap();
+        let destination = tempdir().unwrap();
+        let s = source.path();
+        let d = destination.path();
+
+        fs::create_dir_all(s.join("sub")).unwrap();
+        fs::create_dir_all(s.join("empty")).unwrap();
+        fs::write(s.join("sub/flake.lock"), "lock").unwrap();
+        fs::write(s.join("flake.nix"), "new").unwrap();
+        fs::write(d.join("flake.nix"), "local edit").unwrap();
+
+        let options = SyncOptions {
+            filter: Filter::new()
+                .include("*.lock")
+                .and_then(|f| f.include("*/"))
+                .and_then(|f| f.exclude("*"))
+                .unwrap(),
+            prune_empty_dirs: true,
+            ..Default::default()
+        };
+        let changes = sync(s, d, &options).unwrap();
+
+        assert_eq!(
+            itemized(&changes),
+            vec!["cd+++++++++ sub/", ">f+++++++++ sub/flake.lock"]
+        );
+        assert_eq!(
+            fs::read_to_string(d.join("flake.nix")).unwrap(),
+            "local edit"
+        );
+    }
+}